@@ -10,6 +10,7 @@ IMAGE_DOCKER=$REPO/${NAME}:${ARCH}_${VERSION}
 echo Image docker : $IMAGE_DOCKER
 
 CERT_FOLDER=/home/mathieu/mgdev/certs
+POSTMASTER_FOLDER=/home/mathieu/mgdev/postmaster
 
 export MG_MQ_HOST=mg-dev5.maple.maceroc.com
 export CAFILE=/certs/pki.millegrille
@@ -22,6 +23,7 @@ export MG_NOEUD_ID=43eee47d-fc23-4cf5-b359-70069cf06600
 docker run --rm -it \
   --network host \
   -v $CERT_FOLDER:/certs \
+  -v $POSTMASTER_FOLDER:/var/opt/millegrilles/postmaster \
   -e CAFILE -e KEYFILE -e CERTFILE \
   -e MG_MQ_HOST -e MG_MONGO_HOST \
   -e RUST_LOG \
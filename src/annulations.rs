@@ -0,0 +1,162 @@
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use log::{debug, info};
+use millegrilles_common_rust::futures::future::{Either, select};
+use millegrilles_common_rust::tokio::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+/// Duree pendant laquelle une annulation bloque les nouvelles transmissions du message.
+const DUREE_ANNULATION_SECS: u64 = 24 * 3600;
+
+/// Annulation d'un message, optionnellement limitee a un idmg ou a un fuuid.
+#[derive(Clone, Debug)]
+struct FiltreAnnulation {
+    uuid_message: String,
+    idmg: Option<String>,
+    fuuid: Option<String>,
+    date: Instant,
+}
+
+impl FiltreAnnulation {
+    fn correspond(&self, uuid_message: &str, idmg: Option<&str>, fuuid: Option<&str>) -> bool {
+        if self.uuid_message.as_str() != uuid_message { return false }
+        if let (Some(f), Some(i)) = (self.idmg.as_ref(), idmg) {
+            if f.as_str() != i { return false }
+        }
+        if let (Some(f), Some(u)) = (self.fuuid.as_ref(), fuuid) {
+            if f.as_str() != u { return false }
+        }
+        // Un filtre sur un fuuid ne s'applique pas au message lui-meme
+        ! (self.fuuid.is_some() && fuuid.is_none())
+    }
+}
+
+#[derive(Debug)]
+struct TransfertActif {
+    id: u64,
+    uuid_message: String,
+    idmg: String,
+    fuuid: String,
+    jeton: CancellationToken,
+}
+
+/// Transferts d'attachments en cours et annulations demandees (commande annulerTransmission).
+#[derive(Debug)]
+pub struct Annulations {
+    filtres: Mutex<Vec<FiltreAnnulation>>,
+    transferts: Mutex<Vec<TransfertActif>>,
+    prochain_id: Mutex<u64>,
+}
+
+/// Enregistrement d'un transfert en cours, retire lorsqu'il est drop.
+pub struct GardeTransfert {
+    annulations: Arc<Annulations>,
+    id: u64,
+    jeton: CancellationToken,
+}
+
+impl Annulations {
+    pub fn new() -> Self {
+        Annulations {
+            filtres: Mutex::new(Vec::new()),
+            transferts: Mutex::new(Vec::new()),
+            prochain_id: Mutex::new(0),
+        }
+    }
+
+    /// Enregistre une annulation et interrompt les transferts correspondants. Retourne le nombre
+    /// de transferts interrompus.
+    pub fn annuler(&self, uuid_message: &str, idmg: Option<&str>, fuuid: Option<&str>) -> usize {
+        let filtre = FiltreAnnulation {
+            uuid_message: uuid_message.into(),
+            idmg: idmg.map(|i| i.to_owned()),
+            fuuid: fuuid.map(|f| f.to_owned()),
+            date: Instant::now(),
+        };
+
+        let mut interrompus = 0;
+        for transfert in self.transferts.lock().expect("lock transferts").iter() {
+            if filtre.correspond(transfert.uuid_message.as_str(), Some(transfert.idmg.as_str()), Some(transfert.fuuid.as_str())) {
+                info!("Annulations.annuler Interruption upload {} vers {} (message {})", transfert.fuuid, transfert.idmg, uuid_message);
+                transfert.jeton.cancel();
+                interrompus += 1;
+            }
+        }
+
+        self.filtres.lock().expect("lock filtres").push(filtre);
+        interrompus
+    }
+
+    pub fn est_annule(&self, uuid_message: &str, idmg: Option<&str>, fuuid: Option<&str>) -> bool {
+        let mut filtres = self.filtres.lock().expect("lock filtres");
+        filtres.retain(|f| f.date.elapsed() < Duration::from_secs(DUREE_ANNULATION_SECS));
+        filtres.iter().any(|f| f.correspond(uuid_message, idmg, fuuid))
+    }
+
+    /// Enregistre un transfert d'attachment. Le jeton est deja annule si le transfert a ete annule.
+    pub fn enregistrer(self: &Arc<Self>, uuid_message: &str, idmg: &str, fuuid: &str) -> GardeTransfert {
+        let jeton = CancellationToken::new();
+        if self.est_annule(uuid_message, Some(idmg), Some(fuuid)) {
+            jeton.cancel();
+        }
+
+        let id = {
+            let mut prochain_id = self.prochain_id.lock().expect("lock prochain_id");
+            *prochain_id += 1;
+            *prochain_id
+        };
+
+        self.transferts.lock().expect("lock transferts").push(TransfertActif {
+            id,
+            uuid_message: uuid_message.into(),
+            idmg: idmg.into(),
+            fuuid: fuuid.into(),
+            jeton: jeton.clone(),
+        });
+
+        GardeTransfert { annulations: self.clone(), id, jeton }
+    }
+}
+
+impl GardeTransfert {
+    /// Execute le transfert, interrompu (drop) si le transfert est annule.
+    pub async fn executer<F, T>(&self, transfert: F) -> Result<T, Box<dyn Error>>
+        where F: Future<Output=Result<T, Box<dyn Error>>>
+    {
+        if self.jeton.is_cancelled() {
+            Err(ErreurAnnulee {})?
+        }
+
+        let annulation = self.jeton.cancelled();
+        match select(Box::pin(transfert), Box::pin(annulation)).await {
+            Either::Left((resultat, _)) => resultat,
+            Either::Right(_) => {
+                debug!("GardeTransfert.executer Transfert {} annule", self.id);
+                Err(ErreurAnnulee {})?
+            }
+        }
+    }
+}
+
+impl Drop for GardeTransfert {
+    fn drop(&mut self) {
+        let id = self.id;
+        self.annulations.transferts.lock().expect("lock transferts").retain(|t| t.id != id);
+    }
+}
+
+/// Le transfert a ete annule (commande annulerTransmission).
+#[derive(Debug)]
+pub struct ErreurAnnulee {}
+
+impl fmt::Display for ErreurAnnulee {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Transmission annulee")
+    }
+}
+
+impl Error for ErreurAnnulee {}
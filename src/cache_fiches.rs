@@ -0,0 +1,107 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use log::{debug, info};
+use millegrilles_common_rust::chrono::{DateTime, Duration, Utc};
+
+use crate::config::lire_env;
+use crate::constantes::*;
+use crate::messages_struct::FicheMillegrilleApplication;
+
+const DEFAULT_TTL_SECS: i64 = 300;
+const INTERVALLE_STATISTIQUES_SECS: i64 = 300;
+
+/// Cache des fiches recues de CoreTopologie, par idmg et application.
+#[derive(Debug)]
+pub struct CacheFiches {
+    ttl: Duration,
+    fiches: Mutex<HashMap<(String, String), (DateTime<Utc>, FicheMillegrilleApplication)>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    derniere_statistique: Mutex<DateTime<Utc>>,
+}
+
+impl CacheFiches {
+    pub fn new(ttl: Duration) -> Self {
+        CacheFiches {
+            ttl,
+            fiches: Mutex::new(HashMap::new()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            derniere_statistique: Mutex::new(Utc::now()),
+        }
+    }
+
+    pub fn charger_env() -> Self {
+        let ttl_secs = lire_env(ENV_FICHE_TTL).unwrap_or(DEFAULT_TTL_SECS);
+        CacheFiches::new(Duration::seconds(ttl_secs))
+    }
+
+    pub fn get(&self, idmg: &str, application: &str) -> Option<FicheMillegrilleApplication> {
+        let mut fiches = self.fiches.lock().expect("lock fiches");
+        let cle = (idmg.to_owned(), application.to_owned());
+
+        let fiche = match fiches.get(&cle) {
+            Some((date, fiche)) => match *date + self.ttl > Utc::now() {
+                true => Some(fiche.clone()),
+                false => {
+                    fiches.remove(&cle);
+                    None
+                }
+            },
+            None => None
+        };
+
+        match fiche.is_some() {
+            true => {
+                let hits = self.hits.fetch_add(1, Ordering::Relaxed) + 1;
+                debug!("CacheFiches.get Hit {}/{} (hits: {}, misses: {})", idmg, application, hits, self.misses.load(Ordering::Relaxed));
+            },
+            false => {
+                let misses = self.misses.fetch_add(1, Ordering::Relaxed) + 1;
+                debug!("CacheFiches.get Miss {}/{} (hits: {}, misses: {})", idmg, application, self.hits.load(Ordering::Relaxed), misses);
+            }
+        }
+
+        fiche
+    }
+
+    /// Journalise les compteurs hits/misses (niveau info) au plus une fois par intervalle.
+    /// Appele par le thread d'entretien.
+    pub fn emettre_statistiques(&self) {
+        let maintenant = Utc::now();
+        {
+            let mut derniere = self.derniere_statistique.lock().expect("lock derniere_statistique");
+            if *derniere + Duration::seconds(INTERVALLE_STATISTIQUES_SECS) > maintenant {
+                return
+            }
+            *derniere = maintenant;
+        }
+
+        let hits = self.hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+        let nombre_fiches = self.fiches.lock().expect("lock fiches").len();
+        info!("CacheFiches Statistiques (hits: {}, misses: {}, fiches en cache: {})", hits, misses, nombre_fiches);
+    }
+
+    pub fn inserer(&self, application: &str, fiche: FicheMillegrilleApplication) {
+        let cle = (fiche.idmg.clone(), application.to_owned());
+        self.fiches.lock().expect("lock fiches").insert(cle, (Utc::now(), fiche));
+    }
+
+    /// Retire toutes les fiches d'une millegrille.
+    pub fn invalider(&self, idmg: &str) {
+        let mut fiches = self.fiches.lock().expect("lock fiches");
+        let avant = fiches.len();
+        fiches.retain(|(i, _), _| i.as_str() != idmg);
+        if fiches.len() != avant {
+            info!("CacheFiches.invalider Fiches de {} retirees du cache", idmg);
+        }
+    }
+
+    pub fn invalider_tout(&self) {
+        self.fiches.lock().expect("lock fiches").clear();
+        info!("CacheFiches.invalider_tout Cache de fiches vide");
+    }
+}
@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Mutex;
+
+use log::{debug, warn};
+use millegrilles_common_rust::certificats::calculer_idmg;
+use millegrilles_common_rust::openssl::x509::X509;
+use millegrilles_common_rust::reqwest;
+use millegrilles_common_rust::reqwest::Client;
+use millegrilles_common_rust::tokio::time::Duration;
+
+use crate::config::lire_env;
+use crate::constantes::*;
+use crate::messages_struct::FicheMillegrilleApplication;
+
+const DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 10;
+const DEFAULT_REQUEST_TIMEOUT_SECS: u64 = 120;
+
+/// Politique de verification TLS des millegrilles tierces.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PolitiqueTlsRemote {
+    /// Seul le CA de la fiche (verifie avec l'idmg) est accepte.
+    CaFiche,
+    /// Le CA de la fiche et les autorites web (web PKI) sont acceptes.
+    CaFicheOuWebPki,
+}
+
+impl PolitiqueTlsRemote {
+    pub fn charger_env() -> Self {
+        match lire_env(ENV_TLS_WEBPKI) {
+            Some(true) => PolitiqueTlsRemote::CaFicheOuWebPki,
+            _ => PolitiqueTlsRemote::CaFiche
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ConfigurationClientRemote {
+    pub politique_tls: PolitiqueTlsRemote,
+    pub connect_timeout: Duration,
+    pub request_timeout: Duration,
+}
+
+impl ConfigurationClientRemote {
+    pub fn charger_env() -> Self {
+        ConfigurationClientRemote {
+            politique_tls: PolitiqueTlsRemote::charger_env(),
+            connect_timeout: Duration::from_secs(lire_env(ENV_CONNECT_TIMEOUT).unwrap_or(DEFAULT_CONNECT_TIMEOUT_SECS)),
+            request_timeout: Duration::from_secs(lire_env(ENV_REQUEST_TIMEOUT).unwrap_or(DEFAULT_REQUEST_TIMEOUT_SECS)),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ClientIdmg {
+    ca: Option<String>,
+    client: Client,
+}
+
+/// Clients reqwest par idmg de millegrille tierce, partages par les messages et les attachments
+/// pour conserver le pool de connexions.
+#[derive(Debug)]
+pub struct ClientsRemote {
+    configuration: ConfigurationClientRemote,
+    clients: Mutex<HashMap<String, ClientIdmg>>,
+}
+
+impl ClientsRemote {
+    pub fn new(configuration: ConfigurationClientRemote) -> Self {
+        ClientsRemote { configuration, clients: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn charger_env() -> Self {
+        ClientsRemote::new(ConfigurationClientRemote::charger_env())
+    }
+
+    /// Retourne le client de la millegrille de la fiche. Le client est recree si le CA de la fiche change.
+    pub fn get_client(&self, fiche: &FicheMillegrilleApplication) -> Result<Client, Box<dyn Error>> {
+        let mut clients = self.clients.lock().expect("lock clients");
+        if let Some(c) = clients.get(fiche.idmg.as_str()) {
+            if c.ca == fiche.ca {
+                return Ok(c.client.clone())
+            }
+            debug!("ClientsRemote.get_client CA modifie pour {}, nouveau client", fiche.idmg);
+        }
+
+        let client = new_client_remote(fiche, &self.configuration)?;
+        clients.insert(fiche.idmg.clone(), ClientIdmg { ca: fiche.ca.clone(), client: client.clone() });
+        Ok(client)
+    }
+
+    pub fn retirer(&self, idmg: &str) {
+        self.clients.lock().expect("lock clients").remove(idmg);
+    }
+}
+
+/// Prepare un client pour une millegrille tierce. Le CA de la fiche doit correspondre a l'idmg.
+pub fn new_client_remote(fiche: &FicheMillegrilleApplication, configuration: &ConfigurationClientRemote)
+    -> Result<Client, Box<dyn Error>>
+{
+    let web_pki = configuration.politique_tls == PolitiqueTlsRemote::CaFicheOuWebPki;
+
+    let mut builder = reqwest::Client::builder()
+        .https_only(true)
+        .use_rustls_tls()
+        .http2_adaptive_window(true)
+        .connect_timeout(configuration.connect_timeout)
+        .timeout(configuration.request_timeout)
+        .tls_built_in_root_certs(web_pki);
+
+    match fiche.ca.as_ref() {
+        Some(ca_pem) => {
+            verifier_ca_idmg(ca_pem.as_str(), fiche.idmg.as_str())?;
+            builder = builder.add_root_certificate(reqwest::Certificate::from_pem(ca_pem.as_bytes())?);
+        },
+        None => {
+            if ! web_pki {
+                Err(format!("clients_remote.new_client_remote CA manquant dans la fiche de {}", fiche.idmg))?
+            }
+            warn!("new_client_remote CA manquant dans la fiche de {}, utilisation web PKI", fiche.idmg);
+        }
+    }
+
+    Ok(builder.build()?)
+}
+
+fn verifier_ca_idmg(ca_pem: &str, idmg: &str) -> Result<(), Box<dyn Error>> {
+    let ca_x509 = X509::from_pem(ca_pem.as_bytes())?;
+    let idmg_calcule = calculer_idmg(&ca_x509)?;
+    if idmg_calcule.as_str() != idmg {
+        Err(format!("clients_remote.verifier_ca_idmg CA de la fiche ({}) ne correspond pas a l'idmg {}", idmg_calcule, idmg))?
+    }
+    Ok(())
+}
+
+/// Indique si l'erreur est une erreur de connexion (ou timeout) vers le serveur distant.
+pub fn est_erreur_connexion(err: &(dyn Error + 'static)) -> bool {
+    match err.downcast_ref::<reqwest::Error>() {
+        Some(e) => e.is_connect() || e.is_timeout(),
+        None => false
+    }
+}
@@ -1,20 +1,31 @@
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
+use std::time::Instant;
 use log::{debug, error, info, warn};
-use core::time::Duration;
 use deflate::deflate_bytes_gzip;
 
 use millegrilles_common_rust::certificats::{ValidateurX509, VerificateurPermissions};
+use millegrilles_common_rust::chrono::Utc;
 use millegrilles_common_rust::configuration::IsConfigNoeud;
 use millegrilles_common_rust::constantes::{DELEGATION_GLOBALE_PROPRIETAIRE, RolesCertificats, Securite};
-use millegrilles_common_rust::formatteur_messages::MessageMilleGrille;
+use millegrilles_common_rust::formatteur_messages::{DateEpochSeconds, MessageMilleGrille};
+use millegrilles_common_rust::futures::stream::FuturesUnordered;
 use millegrilles_common_rust::generateur_messages::{GenerateurMessages, RoutageMessageAction};
 use millegrilles_common_rust::recepteur_messages::{MessageValideAction, TypeMessage};
 use millegrilles_common_rust::serde_json;
 use millegrilles_common_rust::serde_json::{json, Value};
+use millegrilles_common_rust::tokio;
+use millegrilles_common_rust::tokio::sync::mpsc::Receiver;
+use millegrilles_common_rust::tokio::time::{Duration, sleep};
+use millegrilles_common_rust::tokio_stream::StreamExt;
 use millegrilles_common_rust::verificateur::VerificateurMessage;
 use millegrilles_common_rust::reqwest;
 
+use crate::config::lire_env;
 use crate::constantes::*;
+use crate::delais_remote::{ErreurRetryAfter, est_status_ralentir, lire_retry_after};
+use crate::file_sortante::{cle_entree, EntreeFileSortante};
 use crate::gestionnaire::GestionnairePostmaster;
 use crate::messages_struct::*;
 use crate::transfert_fichier::*;
@@ -47,12 +58,27 @@ pub async fn consommer_commande<M>(middleware: &M, m: MessageValideAction, gesti
         // Commandes standard
         COMMANDE_POSTER => commande_poster(middleware, m, gestionnaire).await,
         COMMANDE_POUSSER_ATTACHMENT => commande_pousser_attachment(middleware, m, gestionnaire).await,
+        COMMANDE_POUSSER_ATTACHMENT_DESTINATIONS => commande_pousser_attachment_destinations(middleware, m, gestionnaire).await,
+        COMMANDE_ANNULER_TRANSMISSION => commande_annuler_transmission(middleware, m, gestionnaire).await,
+
+        // Commandes d'administration
+        COMMANDE_REJOUER_NON_LIVRABLE => commande_rejouer_non_livrable(middleware, m, gestionnaire).await,
+        COMMANDE_REESSAYER_MAINTENANT => commande_reessayer_maintenant(middleware, m, gestionnaire).await,
+        COMMANDE_PURGER_FILE => commande_purger_file(middleware, m, gestionnaire).await,
+        COMMANDE_SUSPENDRE_IDMG => commande_suspendre_idmg(middleware, m, gestionnaire).await,
+        COMMANDE_REPRENDRE_IDMG => commande_reprendre_idmg(middleware, m, gestionnaire).await,
 
         // Commandes inconnues
         _ => Err(format!("consommer_commande: Commande {} inconnue : {}, message dropped", DOMAINE_NOM, m.action))?,
     }
 }
 
+/// Commandes d'administration : delegation globale ou exchange 3.protege/4.secure.
+pub fn verifier_autorisation_admin(m: &MessageValideAction) -> bool {
+    m.verifier_delegation_globale(DELEGATION_GLOBALE_PROPRIETAIRE) ||
+        m.verifier_exchanges(vec![Securite::L3Protege, Securite::L4Secure])
+}
+
 async fn commande_poster<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnairePostmaster)
     -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
     where M: GenerateurMessages + VerificateurMessage + ValidateurX509
@@ -62,12 +88,12 @@ async fn commande_poster<M>(middleware: &M, m: MessageValideAction, gestionnaire
     let message_poster: CommandePostmasterPoster = m.message.parsed.map_contenu(None)?;
     debug!("commande_poster Message mappe : {:?}", message_poster);
 
-    poster_message(middleware, message_poster).await?;
+    poster_message(middleware, gestionnaire, message_poster).await?;
 
     Ok(None)
 }
 
-async fn poster_message<M>(middleware: &M, message_poster: CommandePostmasterPoster)
+async fn poster_message<M>(middleware: &M, gestionnaire: &GestionnairePostmaster, message_poster: CommandePostmasterPoster)
     -> Result<(), Box<dyn Error>>
     where M: GenerateurMessages + VerificateurMessage + ValidateurX509
 {
@@ -76,109 +102,684 @@ async fn poster_message<M>(middleware: &M, message_poster: CommandePostmasterPos
         serde_json::from_value(value)?
     };
 
-    // Ajouter _certificat et _millegrille au message
-    let message_map = {
-        let mut message_map = message_poster.message;
-        message_map.insert("_certificat".into(), Value::from(message_poster.certificat_message.clone()));
-        message_map.insert("_millegrille".into(), Value::from(message_poster.certificat_millegrille.clone()));
-        message_map
-    };
-
     let uuid_message = match message_mappe.entete {
         Some(e) => Ok(e.uuid_transaction.clone()),
         None => Err(format!("commandes.poster_message Entete manquante du message"))
     }?;
 
-    let mut headers = reqwest::header::HeaderMap::new();
-    headers.insert("Content-Type", reqwest::header::HeaderValue::from_static("application/json"));
-    headers.insert("Content-Encoding", reqwest::header::HeaderValue::from_static("gzip"));
-    let client = reqwest::Client::builder()
-        .default_headers(headers)
-        .connect_timeout(Duration::from_secs(10))
-        .danger_accept_invalid_certs(true)  // TODO : supporter valide/invalide avec upgrade securite emission
-        .build()?;
-
-    for destination in message_poster.destinations {
-
-        let cle_info = &message_poster.cle_info;
-        let message_bytes = {
-            let message_http = json!({
-                "message": &message_map,
-                "chiffrage": {
-                    "cles": &destination.cles,
-                    "domaine": "Messagerie",
-                    "format": &cle_info.format,
-                    "hachage_bytes": &cle_info.hachage_bytes,
-                    "identificateurs_document": {
-                        "message": "true"
-                    },
-                    "iv": &cle_info.iv,
-                    "tag": &cle_info.tag,
-                },
-                "destinataires": &destination.destinataires,
-            });
-            debug!("poster_message POST message {:?}", message_http);
-
-            // Signer le message, compresser en gzip et pousser via https
-            let message_signe = middleware.formatter_message(
-                &message_http, None::<&str>, None::<&str>, None::<&str>, None, true)?;
-            let message_str = serde_json::to_string(&message_signe)?;
-
-            let message_bytes = deflate_bytes_gzip(message_str.as_bytes());
-
-            message_bytes
+    // Transmettre les destinations en parallele, chaque confirmation est emise des que la destination est traitee
+    let mut futures = FuturesUnordered::new();
+    for destination in &message_poster.destinations {
+        // Conserver l'entree dans la file avant la premiere tentative
+        let entree = EntreeFileSortante::new(uuid_message.as_str(), &message_poster, destination.clone());
+        // L'erreur est journalisee avant tout await (Box<dyn Error> n'est pas Send)
+        let erreur_ajout = match gestionnaire.file_sortante.ajouter(&entree) {
+            Ok(()) => false,
+            Err(e) => {
+                error!("poster_message Erreur conservation entree {} dans la file : {:?}", entree.cle(), e);
+                true
+            }
+        };
+        if erreur_ajout {
+            let confirmation = preparer_confirmation(&entree, 500, None);
+            if let Err(e) = transmettre_confirmation(middleware, &confirmation).await {
+                error!("poster_message Erreur confirmation {} : {:?}", entree.cle(), e);
+            }
+            continue
+        }
+
+        futures.push(traiter_entree_file(middleware, gestionnaire, entree));
+    }
+
+    while let Some(resultat) = futures.next().await {
+        if let Err(e) = resultat {
+            error!("poster_message Erreur traitement destination : {:?}", e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Traite les entrees de la file sortante dues pour un retry.
+pub async fn traiter_file_sortante<M>(middleware: &M, gestionnaire: &GestionnairePostmaster)
+    where M: GenerateurMessages + VerificateurMessage + ValidateurX509
+{
+    let entrees = gestionnaire.file_sortante.reserver_pretes();
+    if entrees.len() > 0 {
+        debug!("traiter_file_sortante {} entrees a transmettre", entrees.len());
+    }
+
+    let mut futures = FuturesUnordered::new();
+    for entree in entrees {
+        futures.push(traiter_entree_file(middleware, gestionnaire, entree));
+    }
+
+    while let Some(resultat) = futures.next().await {
+        if let Err(e) = resultat {
+            error!("traiter_file_sortante Erreur traitement entree : {:?}", e);
+        }
+    }
+}
+
+/// Fait une tentative de transmission d'une entree reservee (selon les limites de concurrence), puis la libere.
+async fn traiter_entree_file<M>(middleware: &M, gestionnaire: &GestionnairePostmaster, entree: EntreeFileSortante)
+    -> Result<(), Box<dyn Error>>
+    where M: GenerateurMessages + VerificateurMessage + ValidateurX509
+{
+    let cle = entree.cle();
+    let permis = match gestionnaire.concurrence.acquerir(entree.destination.idmg.as_str()).await {
+        Ok(p) => p,
+        Err(e) => {
+            gestionnaire.file_sortante.liberer(cle.as_str());
+            return Err(e)
+        }
+    };
+    let resultat = tenter_transmission(middleware, gestionnaire, entree).await;
+    drop(permis);
+    gestionnaire.file_sortante.liberer(cle.as_str());
+    resultat
+}
+
+/// Delai avant de reverifier une entree dont l'idmg est suspendu (reprendreIdmg la remet en file immediatement).
+const DELAI_IDMG_SUSPENDU_SECS: i64 = 300;
+
+async fn tenter_transmission<M>(middleware: &M, gestionnaire: &GestionnairePostmaster, mut entree: EntreeFileSortante)
+    -> Result<(), Box<dyn Error>>
+    where M: GenerateurMessages + VerificateurMessage + ValidateurX509
+{
+    if entree.confirmation.is_some() {
+        // Le message a deja ete traite, seule la confirmation reste a emettre
+        return finaliser_entree(middleware, gestionnaire, entree).await
+    }
+
+    if gestionnaire.annulations.est_annule(entree.uuid_message.as_str(), Some(entree.destination.idmg.as_str()), None) {
+        // Annule pendant que l'entree etait reservee, elle n'a pas ete retiree par la commande
+        info!("tenter_transmission Message {} vers {} annule, entree retiree", entree.uuid_message, entree.destination.idmg);
+        gestionnaire.file_sortante.retirer(entree.cle().as_str())?;
+        let evenement = EvenementTransmissionAnnulee {
+            uuid_message: entree.uuid_message.clone(),
+            idmg: Some(entree.destination.idmg.clone()),
+            fuuid: None,
+            idmgs_retires: vec![entree.destination.idmg.clone()],
+            uploads_interrompus: 0,
         };
+        return emettre_evenement_annulation(middleware, &evenement).await
+    }
+
+    if gestionnaire.idmgs_suspendus.est_suspendu(entree.destination.idmg.as_str()) {
+        // Transmissions suspendues par un administrateur, reporter sans compter de tentative
+        debug!("tenter_transmission Message {} vers {} : idmg suspendu", entree.uuid_message, entree.destination.idmg);
+        entree.reporter(DELAI_IDMG_SUSPENDU_SECS);
+        gestionnaire.file_sortante.sauvegarder(&entree)?;
+        return Ok(())
+    }
+
+    if let Some(date) = gestionnaire.delais_remote.get(entree.destination.idmg.as_str()) {
+        // La millegrille distante a demande de ralentir, reporter sans compter de tentative
+        debug!("tenter_transmission Message {} vers {} reporte a {:?}", entree.uuid_message, entree.destination.idmg, date);
+        entree.prochain_essai = DateEpochSeconds::from(date);
+        gestionnaire.file_sortante.sauvegarder(&entree)?;
+        return Ok(())
+    }
+
+    entree.tentatives += 1;
+    entree.retry_after = None;
+
+    let resultat = match transmettre_destination(middleware, gestionnaire, &entree).await {
+        Ok(Some(r)) => Some(r),
+        Ok(None) => {
+            entree.ajouter_historique(503, Some("Aucune URL de la millegrille n'a accepte le message".into()));
+            None
+        },
+        Err(e) => match e.downcast_ref::<ErreurRetryAfter>() {
+            Some(erreur) => {
+                info!("tenter_transmission Message {} vers {} : {}", entree.uuid_message, entree.destination.idmg, erreur);
+                entree.retry_after = Some(erreur.retry_after);
+                entree.ajouter_historique(erreur.status, Some(erreur.to_string()));
+                None
+            },
+            None => {
+                error!("tenter_transmission Erreur transmission message {} vers {} : {:?}",
+                    entree.uuid_message, entree.destination.idmg, e);
+                entree.ajouter_historique(500, Some(raison_erreur_transmission(e.as_ref())));
+                None
+            }
+        }
+    };
 
-        // Emettre message via HTTP POST
-        // Boucler dans la liste des destinations pour la millegrille tierce
-        let mut status_reponse = None;
-        for app_config in &destination.fiche.application {
-            let url_app = app_config.url.as_str();
-            let url_poster = format!("{}/poster", url_app);
-            debug!("Poster message vers {}", url_poster);
-            let res = client.post(url_poster)
-                .body(message_bytes.clone())
-                .send()
-                .await?;
-            debug!("Reponse post HTTP : {:?}", res);
-            if res.status().is_success() {
-                status_reponse = Some(res.status());
-                break;  // On a reussi le transfert, pas besoin de poursuivre
+    match resultat {
+        Some(r) => {
+            entree.ajouter_historique(r.status, None);
+            entree.confirmation = Some(preparer_confirmation(&entree, r.status, r.reponse.as_ref()));
+            finaliser_entree(middleware, gestionnaire, entree).await?;
+        },
+        None => {
+            if entree.retry_epuises() {
+                warn!("tenter_transmission Message {} vers {} : echec apres {} tentatives",
+                    entree.uuid_message, entree.destination.idmg, entree.tentatives);
+                let code = entree.dernier_code.unwrap_or(503);
+                entree.confirmation = Some(preparer_confirmation(&entree, code, None));
+                entree.non_livrable = true;
+                finaliser_entree(middleware, gestionnaire, entree).await?;
+            } else {
+                entree.planifier_retry();
+                debug!("tenter_transmission Message {} vers {} : tentative {} echouee, retry a {:?}",
+                    entree.uuid_message, entree.destination.idmg, entree.tentatives, entree.prochain_essai);
+                gestionnaire.file_sortante.sauvegarder(&entree)?;
             }
         }
+    }
+
+    Ok(())
+}
+
+/// Raison courte d'un echec de transmission pour l'historique. L'historique est emis avec l'evenement
+/// non livrable (exchange public) : le detail de l'erreur reste dans le journal du postmaster.
+fn raison_erreur_transmission(e: &(dyn Error + 'static)) -> String {
+    match e.downcast_ref::<reqwest::Error>() {
+        Some(e) if e.is_timeout() => "Delai de connexion expire".into(),
+        Some(e) if e.is_connect() => "Connexion a la millegrille impossible".into(),
+        Some(_) => "Erreur http de transmission".into(),
+        None => "Erreur interne de transmission".into(),
+    }
+}
+
+/// Emet la confirmation de l'entree puis la retire de la file. Si la confirmation echoue,
+/// l'entree est conservee pour reemettre seulement la confirmation.
+/// Une entree non livrable est deplacee vers les non livrables avec un evenement de bounce.
+async fn finaliser_entree<M>(middleware: &M, gestionnaire: &GestionnairePostmaster, mut entree: EntreeFileSortante)
+    -> Result<(), Box<dyn Error>>
+    where M: GenerateurMessages
+{
+    let resultat = match entree.confirmation.as_ref() {
+        Some(c) => transmettre_confirmation(middleware, c).await,
+        None => Err(format!("commandes.finaliser_entree Confirmation manquante pour {}", entree.cle()))?
+    };
+    // Ne pas conserver le Box<dyn Error> (non Send) durant l'emission de l'evenement
+    let erreur_confirmation = resultat.err().map(|e| format!("{:?}", e));
+
+    match erreur_confirmation {
+        None => match entree.non_livrable {
+            true => {
+                if let Err(e) = emettre_evenement_non_livrable(middleware, &entree).await {
+                    error!("finaliser_entree Erreur emission evenement non livrable {} : {:?}", entree.cle(), e);
+                }
+                gestionnaire.file_sortante.deplacer_non_livrable(&entree)?
+            },
+            false => gestionnaire.file_sortante.retirer(entree.cle().as_str())?
+        },
+        Some(e) => {
+            error!("finaliser_entree Erreur confirmation {}, retry plus tard : {}", entree.cle(), e);
+            entree.planifier_retry();
+            gestionnaire.file_sortante.sauvegarder(&entree)?;
+        }
+    }
+
+    Ok(())
+}
+
+struct ResultatPoster {
+    status: u16,
+    reponse: Option<ReponsePosterMessage>,
+}
 
-        let code_reponse = match status_reponse {
-            Some(r) => r.as_u16(),
-            None => 503
+fn preparer_message_destination(entree: &EntreeFileSortante) -> Value {
+    let destination = &entree.destination;
+
+    // Ajouter _certificat et _millegrille au message
+    let message_map = {
+        let mut message_map = entree.message.clone();
+        message_map.insert("_certificat".into(), Value::from(entree.certificat_message.clone()));
+        message_map.insert("_millegrille".into(), Value::from(entree.certificat_millegrille.clone()));
+        message_map
+    };
+
+    let cle_info = &entree.cle_info;
+    json!({
+        "message": &message_map,
+        "chiffrage": {
+            "cles": &destination.cles,
+            "domaine": "Messagerie",
+            "format": &cle_info.format,
+            "hachage_bytes": &cle_info.hachage_bytes,
+            "identificateurs_document": {
+                "message": "true"
+            },
+            "iv": &cle_info.iv,
+            "tag": &cle_info.tag,
+        },
+        "destinataires": &destination.destinataires,
+    })
+}
+
+/// Signe et poste le message vers la millegrille de destination.
+/// Retourne la premiere reponse definitive, None si aucune URL n'a accepte le message.
+async fn transmettre_destination<M>(middleware: &M, gestionnaire: &GestionnairePostmaster, entree: &EntreeFileSortante)
+    -> Result<Option<ResultatPoster>, Box<dyn Error>>
+    where M: GenerateurMessages + VerificateurMessage + ValidateurX509
+{
+    let destination = &entree.destination;
+    let message_http = preparer_message_destination(entree);
+
+    if destination.idmg.as_str() == middleware.idmg() {
+        // Destination locale, pas besoin de passer par https
+        return livrer_local(middleware, &message_http).await
+    }
+
+    let client = gestionnaire.clients_remote.get_client(&destination.fiche)?;
+
+    let message_bytes = {
+        debug!("poster_message POST message {:?}", message_http);
+
+        // Signer le message, compresser en gzip et pousser via https
+        let message_signe = middleware.formatter_message(
+            &message_http, None::<&str>, None::<&str>, None::<&str>, None, true)?;
+        let message_str = serde_json::to_string(&message_signe)?;
+
+        let message_bytes = deflate_bytes_gzip(message_str.as_bytes());
+
+        message_bytes
+    };
+
+    // Emettre message via HTTP POST
+    // Boucler dans la liste des URLs candidates pour la millegrille tierce (fiche, adresses, dns)
+    let urls_app = gestionnaire.endpoints.candidats(&destination.fiche, Some(&destination.mapping));
+    let mut resultat = None;
+    let mut erreurs_connexion = true;
+    for url_app in &urls_app {
+        let url_poster = format!("{}/poster", url_app);
+        debug!("Poster message vers {}", url_poster);
+        let res = match client.post(url_poster.as_str())
+            .header("Content-Type", "application/json")
+            .header("Content-Encoding", "gzip")
+            .body(message_bytes.clone())
+            .send()
+            .await
+        {
+            Ok(r) => r,
+            Err(e) => {
+                // Erreur de transport, on essaie la prochaine URL
+                warn!("transmettre_destination Erreur POST vers {} : {:?}", url_poster, e);
+                continue
+            }
         };
+        debug!("Reponse post HTTP : {:?}", res);
+        let status = res.status();
+        erreurs_connexion = false;
+        if est_status_ralentir(status) {
+            // La millegrille distante demande de ralentir, inutile d'essayer ses autres URLs
+            let retry_after = lire_retry_after(res.headers());
+            gestionnaire.delais_remote.reporter(destination.idmg.as_str(), retry_after);
+            Err(ErreurRetryAfter { status: status.as_u16(), retry_after })?
+        } else if status.is_success() {
+            gestionnaire.endpoints.set_prefere(destination.idmg.as_str(), url_app.as_str());
+            let reponse = lire_reponse_poster(res).await;
+            resultat = Some(ResultatPoster { status: status.as_u16(), reponse });
+            break;  // On a reussi le transfert, pas besoin de poursuivre
+        } else if status.is_client_error() {
+            // Refus avec resultat par destinataire : reponse definitive, pas de retry
+            if let Some(reponse) = lire_reponse_poster(res).await {
+                if reponse.destinataires.is_some() {
+                    resultat = Some(ResultatPoster { status: status.as_u16(), reponse: Some(reponse) });
+                    break;
+                }
+            }
+        }
+    }
+
+    if erreurs_connexion {
+        // Aucune URL n'a repondu, la fiche connue est possiblement perimee
+        gestionnaire.cache_fiches.invalider(destination.idmg.as_str());
+    }
+
+    Ok(resultat)
+}
+
+/// Remet le message directement au domaine Messagerie local via MQ.
+async fn livrer_local<M>(middleware: &M, message_http: &Value)
+    -> Result<Option<ResultatPoster>, Box<dyn Error>>
+    where M: GenerateurMessages
+{
+    debug!("livrer_local Message pour la millegrille locale : {:?}", message_http);
+    let routage = RoutageMessageAction::builder(DOMAINE_MESSAGERIE, COMMANDE_RECEVOIR)
+        .exchanges(vec![Securite::L2Prive])
+        .build();
+
+    let reponse = match middleware.transmettre_commande(routage, message_http, true).await? {
+        Some(TypeMessage::Valide(m)) => match m.message.parsed.map_contenu::<ReponsePosterMessage>(None) {
+            Ok(r) => Some(r),
+            Err(e) => {
+                debug!("livrer_local Reponse sans resultat par destinataire : {:?}", e);
+                None
+            }
+        },
+        _ => Err(format!("commandes.livrer_local Aucune reponse valide de {}", DOMAINE_MESSAGERIE))?
+    };
+
+    if let Some(r) = reponse.as_ref() {
+        if r.ok == Some(false) && r.destinataires.is_none() {
+            // Erreur de traitement locale sans resultat par destinataire, sera reessayee
+            warn!("livrer_local Erreur livraison locale : {:?}", r);
+            return Ok(None)
+        }
+    }
 
-        let mut confirmations = Vec::new();
-        let idmg = destination.idmg;
-        for destinataire in destination.destinataires {
-            let conf_dest = ConfirmationTransmissionDestinataire {
-                destinataire,
-                code: code_reponse as u32,  // TODO code par usager (e.g. 404, usager non trouve)
-            };
-            confirmations.push(conf_dest);
-        }
-        let confirmation = ConfirmationTransmission {
-            uuid_message: uuid_message.clone(),
-            idmg,
-            destinataires: confirmations,
-            code: code_reponse,
+    Ok(Some(ResultatPoster { status: 200, reponse }))
+}
+
+async fn lire_reponse_poster(res: reqwest::Response) -> Option<ReponsePosterMessage> {
+    match res.json::<ReponsePosterMessage>().await {
+        Ok(r) => {
+            debug!("lire_reponse_poster Reponse : {:?}", r);
+            Some(r)
+        },
+        Err(e) => {
+            debug!("lire_reponse_poster Reponse sans resultat par destinataire : {:?}", e);
+            None
+        }
+    }
+}
+
+fn preparer_confirmation(entree: &EntreeFileSortante, code_reponse: u16, reponse: Option<&ReponsePosterMessage>)
+    -> ConfirmationTransmission
+{
+    let mut confirmations = Vec::new();
+    for destinataire in &entree.destination.destinataires {
+        // Utiliser le code par usager de la millegrille tierce, sinon le code global
+        let resultat_destinataire = match reponse {
+            Some(r) => r.get_destinataire(destinataire.as_str()),
+            None => None
+        };
+        let conf_dest = match resultat_destinataire {
+            Some(r) => ConfirmationTransmissionDestinataire {
+                destinataire: destinataire.clone(),
+                code: r.code,
+                raison: r.raison.clone(),
+            },
+            None => ConfirmationTransmissionDestinataire {
+                destinataire: destinataire.clone(),
+                code: code_reponse as u32,
+                raison: None,
+            }
         };
+        confirmations.push(conf_dest);
+    }
 
-        // Transmettre commande confirmation
-        let routage = RoutageMessageAction::builder("Messagerie", "confirmerTransmission")
-            .exchanges(vec![Securite::L1Public])
-            .build();
-        middleware.transmettre_commande(routage, &confirmation, false).await?;
+    ConfirmationTransmission {
+        uuid_message: entree.uuid_message.clone(),
+        idmg: entree.destination.idmg.clone(),
+        destinataires: confirmations,
+        code: code_reponse,
+        retry_after: entree.retry_after,
     }
+}
+
+async fn emettre_evenement_non_livrable<M>(middleware: &M, entree: &EntreeFileSortante)
+    -> Result<(), Box<dyn Error>>
+    where M: GenerateurMessages
+{
+    let raison = match entree.historique.last() {
+        Some(t) => t.raison.clone(),
+        None => None
+    };
+    let evenement = EvenementNonLivrable {
+        uuid_message: entree.uuid_message.clone(),
+        idmg: entree.destination.idmg.clone(),
+        destinataires: entree.destination.destinataires.clone(),
+        code: entree.dernier_code.unwrap_or(503),
+        raison,
+        tentatives: entree.tentatives,
+        historique: entree.historique.clone(),
+    };
+
+    let routage = RoutageMessageAction::builder(DOMAINE_NOM, EVENEMENT_NON_LIVRABLE)
+        .exchanges(vec![Securite::L1Public])
+        .build();
+    middleware.emettre_evenement(routage, &evenement).await?;
+
+    Ok(())
+}
+
+async fn transmettre_confirmation<M>(middleware: &M, confirmation: &ConfirmationTransmission)
+    -> Result<(), Box<dyn Error>>
+    where M: GenerateurMessages
+{
+    let routage = RoutageMessageAction::builder(DOMAINE_MESSAGERIE, COMMANDE_CONFIRMER_TRANSMISSION)
+        .exchanges(vec![Securite::L1Public])
+        .build();
+    middleware.transmettre_commande(routage, confirmation, false).await?;
 
     Ok(())
 }
 
-async fn commande_pousser_attachment<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnairePostmaster)
+async fn commande_rejouer_non_livrable<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnairePostmaster)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + VerificateurMessage + ValidateurX509
+{
+    if ! verifier_autorisation_admin(&m) {
+        Err(format!("commandes.commande_rejouer_non_livrable Autorisation invalide pour message {:?}", m.correlation_id))?
+    }
+
+    let commande: CommandeRejouerNonLivrable = m.message.parsed.map_contenu(None)?;
+    debug!("commande_rejouer_non_livrable Commande : {:?}", commande);
+
+    let cle = cle_entree(commande.uuid_message.as_str(), commande.idmg.as_str())?;
+    let entree = match gestionnaire.file_sortante.rejouer_non_livrable(commande.uuid_message.as_str(), commande.idmg.as_str())? {
+        Some(e) => e,
+        None => {
+            let reponse = json!({"ok": false, "err": "Message non livrable inconnu"});
+            return Ok(Some(middleware.formatter_reponse(&reponse, None)?))
+        }
+    };
+
+    if let Err(e) = traiter_entree_file(middleware, gestionnaire, entree).await {
+        error!("commande_rejouer_non_livrable Erreur traitement {} : {:?}", cle, e);
+    }
+
+    let reponse = json!({"ok": true});
+    Ok(Some(middleware.formatter_reponse(&reponse, None)?))
+}
+
+/// Force un essai immediat des entrees, en ignorant le backoff et le Retry-After de la millegrille
+/// distante.
+/// Un idmg suspendu reste suspendu.
+async fn commande_reessayer_maintenant<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnairePostmaster)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + VerificateurMessage + ValidateurX509
+{
+    if ! verifier_autorisation_admin(&m) {
+        Err(format!("commandes.commande_reessayer_maintenant Autorisation invalide pour message {:?}", m.correlation_id))?
+    }
+
+    let commande: CommandeReessayerMaintenant = m.message.parsed.map_contenu(None)?;
+    debug!("commande_reessayer_maintenant Commande : {:?}", commande);
+
+    let entrees = gestionnaire.file_sortante.reessayer_maintenant(
+        commande.uuid_message.as_ref().map(|u| u.as_str()), commande.idmg.as_ref().map(|i| i.as_str()))?;
+    let idmgs: HashSet<&str> = entrees.iter().map(|e| e.destination.idmg.as_str()).collect();
+    for idmg in idmgs {
+        gestionnaire.delais_remote.retirer(idmg);
+        if gestionnaire.idmgs_suspendus.est_suspendu(idmg) {
+            warn!("commande_reessayer_maintenant Transmissions vers {} suspendues, entrees conservees", idmg);
+        }
+    }
+    // Les entrees sont transmises immediatement par le thread d'entretien, sans bloquer la Q de commandes
+    info!("commande_reessayer_maintenant {} entrees a reessayer", entrees.len());
+    if entrees.len() > 0 {
+        gestionnaire.reveil_file_sortante.notify_one();
+    }
+
+    let reponse = json!({"ok": true, "entrees": entrees.len()});
+    Ok(Some(middleware.formatter_reponse(&reponse, None)?))
+}
+
+/// Retire des entrees de la file (et optionnellement des non livrables) sans les livrer.
+async fn commande_purger_file<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnairePostmaster)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + VerificateurMessage + ValidateurX509
+{
+    if ! verifier_autorisation_admin(&m) {
+        Err(format!("commandes.commande_purger_file Autorisation invalide pour message {:?}", m.correlation_id))?
+    }
+
+    let commande: CommandePurgerFile = m.message.parsed.map_contenu(None)?;
+    debug!("commande_purger_file Commande : {:?}", commande);
+
+    let uuid_message = commande.uuid_message.as_ref().map(|u| u.as_str());
+    let idmg = commande.idmg.as_ref().map(|i| i.as_str());
+    if uuid_message.is_none() && idmg.is_none() {
+        let reponse = json!({"ok": false, "err": "uuid_message ou idmg requis"});
+        return Ok(Some(middleware.formatter_reponse(&reponse, None)?))
+    }
+
+    let retirees = gestionnaire.file_sortante.retirer_entrees(uuid_message, idmg)?;
+    let non_livrables = match commande.non_livrables {
+        Some(true) => gestionnaire.file_sortante.purger_non_livrables(uuid_message, idmg)?,
+        _ => 0
+    };
+    info!("commande_purger_file Message {:?} idmg {:?} : {} entrees et {} non livrables retires",
+        uuid_message, idmg, retirees.len(), non_livrables);
+
+    let reponse = json!({"ok": true, "entrees": retirees.len(), "non_livrables": non_livrables});
+    Ok(Some(middleware.formatter_reponse(&reponse, None)?))
+}
+
+/// Suspend les transmissions (messages et attachments) vers une millegrille tierce.
+async fn commande_suspendre_idmg<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnairePostmaster)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + VerificateurMessage + ValidateurX509
+{
+    if ! verifier_autorisation_admin(&m) {
+        Err(format!("commandes.commande_suspendre_idmg Autorisation invalide pour message {:?}", m.correlation_id))?
+    }
+
+    let commande: CommandeSuspensionIdmg = m.message.parsed.map_contenu(None)?;
+    let modifie = gestionnaire.idmgs_suspendus.suspendre(commande.idmg.as_str())?;
+    info!("commande_suspendre_idmg Transmissions vers {} suspendues (modifie : {})", commande.idmg, modifie);
+
+    let reponse = json!({"ok": true, "modifie": modifie});
+    Ok(Some(middleware.formatter_reponse(&reponse, None)?))
+}
+
+/// Reprend les transmissions vers une millegrille tierce, les entrees en attente sont reessayees
+/// immediatement. Les attachments reportes durant la suspension sont repousses
+/// par Messagerie (evenements d'erreur avec retry_after).
+async fn commande_reprendre_idmg<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnairePostmaster)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + VerificateurMessage + ValidateurX509
+{
+    if ! verifier_autorisation_admin(&m) {
+        Err(format!("commandes.commande_reprendre_idmg Autorisation invalide pour message {:?}", m.correlation_id))?
+    }
+
+    let commande: CommandeSuspensionIdmg = m.message.parsed.map_contenu(None)?;
+    let modifie = gestionnaire.idmgs_suspendus.reprendre(commande.idmg.as_str())?;
+    let entrees = gestionnaire.file_sortante.reessayer_maintenant(None, Some(commande.idmg.as_str()))?;
+    if entrees.len() > 0 {
+        gestionnaire.reveil_file_sortante.notify_one();
+    }
+    info!("commande_reprendre_idmg Transmissions vers {} reprises (modifie : {}), {} entrees en attente",
+        commande.idmg, modifie, entrees.len());
+
+    let reponse = json!({"ok": true, "modifie": modifie, "entrees": entrees.len()});
+    Ok(Some(middleware.formatter_reponse(&reponse, None)?))
+}
+
+/// Annule la transmission d'un message : les retry en attente sont retires de la file et les uploads
+/// d'attachments en cours sont interrompus.
+async fn commande_annuler_transmission<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnairePostmaster)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + VerificateurMessage + ValidateurX509
+{
+    let commande: CommandeAnnulerTransmission = m.message.parsed.map_contenu(None)?;
+    debug!("commande_annuler_transmission Commande : {:?}", commande);
+
+    if ! verifier_autorisation_message(middleware, &m, gestionnaire, commande.uuid_message.as_str()).await {
+        Err(format!("commandes.commande_annuler_transmission Autorisation invalide pour message {:?}", m.correlation_id))?
+    }
+
+    let uuid_message = commande.uuid_message.as_str();
+    let idmg = commande.idmg.as_ref().map(|i| i.as_str());
+    let fuuid = commande.fuuid.as_ref().map(|f| f.as_str());
+
+    // Enregistrer l'annulation avant de vider la file, une entree reservee sera retiree a sa prochaine tentative
+    let uploads_interrompus = gestionnaire.annulations.annuler(uuid_message, idmg, fuuid);
+    let idmgs_retires = match fuuid {
+        Some(_) => Vec::new(),  // Annulation d'un seul attachment, le message est conserve
+        None => gestionnaire.file_sortante.retirer_message(uuid_message, idmg)?
+    };
+    info!("commande_annuler_transmission Message {} : destinations retirees {:?}, {} uploads interrompus",
+        uuid_message, idmgs_retires, uploads_interrompus);
+
+    let evenement = EvenementTransmissionAnnulee {
+        uuid_message: commande.uuid_message.clone(),
+        idmg: commande.idmg.clone(),
+        fuuid: commande.fuuid.clone(),
+        idmgs_retires,
+        uploads_interrompus,
+    };
+    emettre_evenement_annulation(middleware, &evenement).await?;
+
+    let reponse = json!({"ok": true, "idmgs_retires": &evenement.idmgs_retires, "uploads_interrompus": uploads_interrompus});
+    Ok(Some(middleware.formatter_reponse(&reponse, None)?))
+}
+
+/// Acces a la transmission d'un message (annulation, etat) permis a un administrateur, au domaine
+/// Messagerie (qui verifie le proprietaire du brouillon) ou a l'usager proprietaire du message. Le
+/// proprietaire est l'usager du certificat du message conserve dans la file sortante (ou les non livrables).
+pub async fn verifier_autorisation_message<M>(middleware: &M, m: &MessageValideAction, gestionnaire: &GestionnairePostmaster, uuid_message: &str)
+    -> bool
+    where M: ValidateurX509
+{
+    if verifier_autorisation_admin(m) || m.verifier_domaines(vec![DOMAINE_MESSAGERIE.into()]) {
+        return true
+    }
+
+    let user_id = match m.get_user_id() {
+        Some(u) => u,
+        None => return false
+    };
+
+    let entree = match gestionnaire.file_sortante.lister(Some(uuid_message), None).into_iter().next() {
+        Some((entree, _)) => Some(entree),
+        None => match gestionnaire.file_sortante.lister_non_livrables() {
+            Ok(non_livrables) => non_livrables.into_iter().find(|e| e.uuid_message.as_str() == uuid_message),
+            Err(e) => {
+                warn!("verifier_autorisation_message Erreur lecture des non livrables : {:?}", e);
+                None
+            }
+        }
+    };
+    let certificat_message = match entree {
+        Some(e) => e.certificat_message,
+        None => {
+            debug!("verifier_autorisation_message Message {} absent de la file, proprietaire inconnu", uuid_message);
+            return false
+        }
+    };
+
+    match middleware.charger_enveloppe(&certificat_message, None, None).await {
+        Ok(enveloppe) => enveloppe.get_user_id().as_ref() == Some(&user_id),
+        Err(e) => {
+            warn!("verifier_autorisation_message Certificat du message {} invalide : {:?}", uuid_message, e);
+            false
+        }
+    }
+}
+
+async fn emettre_evenement_annulation<M>(middleware: &M, evenement: &EvenementTransmissionAnnulee)
+    -> Result<(), Box<dyn Error>>
+    where M: GenerateurMessages
+{
+    let routage = RoutageMessageAction::builder(DOMAINE_NOM, EVENEMENT_TRANSMISSION_ANNULEE)
+        .exchanges(vec![Securite::L1Public])
+        .build();
+    middleware.emettre_evenement(routage, evenement).await?;
+
+    Ok(())
+}
+
+async fn commande_pousser_attachment<M>(_middleware: &M, m: MessageValideAction, gestionnaire: &GestionnairePostmaster)
     -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
     where M: GenerateurMessages + VerificateurMessage + ValidateurX509 + IsConfigNoeud
 {
@@ -187,40 +788,445 @@ async fn commande_pousser_attachment<M>(middleware: &M, m: MessageValideAction,
     let message_poster: CommandePousserAttachments = m.message.parsed.map_contenu(None)?;
     debug!("commande_pousser_attachment Message mappe : {:?}", message_poster);
 
-    let fiche = get_fiche(middleware, &message_poster).await?;
-    let uuid_message = message_poster.uuid_message.as_str();
+    ajouter_travail_attachments(gestionnaire, TravailAttachments::Destination(message_poster))?;
+
+    Ok(None)
+}
+
+/// Pousse les attachments d'un message vers plusieurs millegrilles tierces. Un fuuid attendu par
+/// plusieurs destinations est lu une seule fois et distribue a toutes ces destinations.
+async fn commande_pousser_attachment_destinations<M>(_middleware: &M, m: MessageValideAction, gestionnaire: &GestionnairePostmaster)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + VerificateurMessage + ValidateurX509 + IsConfigNoeud
+{
+    let commande: CommandePousserAttachmentDestinations = m.message.parsed.map_contenu(None)?;
+    debug!("commande_pousser_attachment_destinations Commande : {:?}", commande);
+
+    ajouter_travail_attachments(gestionnaire, TravailAttachments::Destinations(commande))?;
+
+    Ok(None)
+}
+
+/// Upload d'attachments en attente dans la queue d'attachments.
+#[derive(Debug)]
+pub enum TravailAttachments {
+    /// Commande pousserAttachment
+    Destination(CommandePousserAttachments),
+    /// Commande pousserAttachmentDestinations
+    Destinations(CommandePousserAttachmentDestinations),
+    /// Destinations d'un message (uuid_message) dont un attachment en echec attendait son delai de retry.
+    Reprise(String, Vec<DestinationAttachments>),
+}
+
+/// Les uploads (limites en debit) sont executes par la queue d'attachments pour ne pas retenir la
+/// Q de commandes, la commande poster n'attend jamais apres un upload.
+fn ajouter_travail_attachments(gestionnaire: &GestionnairePostmaster, travail: TravailAttachments)
+    -> Result<(), Box<dyn Error>>
+{
+    if let Err(e) = gestionnaire.tx_attachments.try_send(travail) {
+        Err(format!("commandes.ajouter_travail_attachments Queue d'attachments non disponible : {}", e))?
+    }
+    Ok(())
+}
 
-    // TODO Requete vers messagerie pour recuperer les fuuids a uploader
+/// Execute les travaux de la queue d'attachments, au plus MG_POSTMASTER_CONCURRENCE_ATTACHMENTS en parallele.
+/// Un travail dont un attachment attend son delai de retry est replanifie sans occuper de place.
+pub async fn executer_travaux_attachments<M>(middleware: &M, gestionnaire: &GestionnairePostmaster, mut rx: Receiver<TravailAttachments>)
+    where M: GenerateurMessages + VerificateurMessage + ValidateurX509 + IsConfigNoeud
+{
+    let limite: usize = lire_env(ENV_CONCURRENCE_ATTACHMENTS).unwrap_or(DEFAULT_CONCURRENCE_ATTACHMENTS).max(1);
+    info!("executer_travaux_attachments Debut queue d'attachments ({} en parallele)", limite);
+
+    let mut travaux = FuturesUnordered::new();
+    let mut reportes = FuturesUnordered::new();
     loop {
-        let prochain_attachment = get_prochain_attachment(middleware, &message_poster).await?;
+        tokio::select! {
+            Some(travail) = rx.recv(), if travaux.len() < limite => {
+                travaux.push(executer_travail_attachments(middleware, gestionnaire, travail));
+            },
+            Some(reprise) = travaux.next(), if ! travaux.is_empty() => {
+                if let Some((delai, travail)) = reprise {
+                    debug!("executer_travaux_attachments Reprise dans {:?}", delai);
+                    reportes.push(async move {
+                        sleep(delai).await;
+                        travail
+                    });
+                }
+            },
+            Some(travail) = reportes.next(), if ! reportes.is_empty() => {
+                travaux.push(executer_travail_attachments(middleware, gestionnaire, travail));
+            },
+            else => break
+        }
+    }
 
-        if ! prochain_attachment.ok {
-            debug!("commande_pousser_attachment Reponse prochain attachement ok=false, on termine");
+    info!("executer_travaux_attachments Fin queue d'attachments");
+}
+
+/// Pousse les attachments du travail. Retourne le travail a reprendre apres le delai de retry d'un
+/// attachment en echec.
+async fn executer_travail_attachments<M>(middleware: &M, gestionnaire: &GestionnairePostmaster, travail: TravailAttachments)
+    -> Option<(Duration, TravailAttachments)>
+    where M: GenerateurMessages + VerificateurMessage + ValidateurX509 + IsConfigNoeud
+{
+    let (uuid_message, destinations) = match travail {
+        TravailAttachments::Destination(commande) => {
+            let destinations = preparer_destinations(middleware, gestionnaire, commande.uuid_message.as_str(), vec![commande.idmg_destination]).await;
+            (commande.uuid_message, destinations)
+        },
+        TravailAttachments::Destinations(commande) => {
+            let destinations = preparer_destinations(middleware, gestionnaire, commande.uuid_message.as_str(), commande.idmgs_destination).await;
+            (commande.uuid_message, destinations)
+        },
+        TravailAttachments::Reprise(uuid_message, destinations) => (uuid_message, destinations),
+    };
+
+    let (delai, destinations) = pousser_attachments(middleware, gestionnaire, uuid_message.as_str(), destinations).await?;
+    Some((delai, TravailAttachments::Reprise(uuid_message, destinations)))
+}
+
+/// Prepare le suivi de chaque destination. Les attachments d'une destination locale sont confirmes
+/// directement (fichiers deja presents).
+async fn preparer_destinations<M>(middleware: &M, gestionnaire: &GestionnairePostmaster, uuid_message: &str, idmgs: Vec<String>)
+    -> Vec<DestinationAttachments>
+    where M: GenerateurMessages + VerificateurMessage + ValidateurX509 + IsConfigNoeud
+{
+    let mut destinations = Vec::new();
+    for idmg in idmgs {
+        let message_poster = CommandePousserAttachments {
+            uuid_message: uuid_message.into(),
+            idmg_destination: idmg,
+        };
+        let idmg = message_poster.idmg_destination.as_str();
+
+        if idmg == middleware.idmg() {
+            if let Err(e) = confirmer_attachments_locaux(middleware, &message_poster).await {
+                error!("preparer_destinations Erreur confirmation attachments locaux : {:?}", e);
+            }
+            continue
         }
 
-        // Uploader l'attachment
-        match prochain_attachment.fuuid.as_ref() {
-            Some(f) => uploader_attachment(middleware, gestionnaire, &fiche, f.as_str(), uuid_message).await?,
-            None => {
-                debug!("commande_pousser_attachment Aucun fuuid recu, on termine");
+        match get_fiche(middleware, gestionnaire, &message_poster).await {
+            Ok(fiche) => destinations.push(DestinationAttachments::new(message_poster, fiche)),
+            Err(e) => error!("preparer_destinations Fiche de {} non disponible : {:?}", idmg, e)
+        }
+    }
+    destinations
+}
+
+/// Pousse les attachments d'un message vers ses destinations. Un fuuid attendu par plusieurs
+/// destinations est lu une seule fois et distribue a toutes ces destinations. Retourne le delai et
+/// les destinations a reprendre lorsque le prochain fuuid est en attente de retry.
+async fn pousser_attachments<M>(middleware: &M, gestionnaire: &GestionnairePostmaster, uuid_message: &str, mut destinations: Vec<DestinationAttachments>)
+    -> Option<(Duration, Vec<DestinationAttachments>)>
+    where M: GenerateurMessages + VerificateurMessage + ValidateurX509 + IsConfigNoeud
+{
+    loop {
+        // Regrouper les destinations actives par prochain fuuid
+        let mut par_fuuid: HashMap<String, Vec<usize>> = HashMap::new();
+        for (idx, destination) in destinations.iter_mut().enumerate() {
+            if ! destination.active { continue }
+            match prochain_fuuid(middleware, gestionnaire, destination).await {
+                Some(fuuid) => par_fuuid.entry(fuuid).or_default().push(idx),
+                None => destination.active = false
+            }
+        }
+
+        // Traiter le fuuid attendu par le plus de destinations, les autres seront retournes au prochain tour
+        let (fuuid, indices) = match par_fuuid.into_iter().max_by_key(|(_, indices)| indices.len()) {
+            Some(groupe) => groupe,
+            None => break
+        };
+
+        let delai = indices.iter().filter_map(|i| destinations[*i].delai_retry(fuuid.as_str())).max();
+        if let Some(delai) = delai {
+            // Le retry est replanifie par la queue d'attachments, sans retenir de place durant le delai
+            return Some((delai, destinations))
+        }
+
+        for i in &indices {
+            destinations[*i].demarrer(fuuid.as_str());
+        }
+
+        let codes = match indices.len() {
+            1 => {
+                let destination = &destinations[indices[0]];
+                let mut codes = HashMap::new();
+                match uploader_attachment(middleware, gestionnaire, &destination.fiche, fuuid.as_str(), uuid_message).await {
+                    Ok(c) => { codes.insert(destination.fiche.idmg.clone(), c); },
+                    Err(e) => error!("pousser_attachments Erreur upload {} : {:?}", fuuid, e)
+                }
+                codes
+            },
+            _ => {
+                let fiches = indices.iter().map(|i| destinations[*i].fiche.clone()).collect();
+                match uploader_attachment_destinations(middleware, gestionnaire, &fiches, fuuid.as_str(), uuid_message).await {
+                    Ok(c) => c,
+                    Err(e) => {
+                        error!("pousser_attachments Erreur upload {} : {:?}", fuuid, e);
+                        HashMap::new()
+                    }
+                }
+            }
+        };
+
+        for i in indices {
+            let destination = &mut destinations[i];
+            let code = codes.get(destination.fiche.idmg.as_str()).cloned();
+            destination.terminer(fuuid.as_str(), code);
+        }
+    }
+
+    for destination in &destinations {
+        destination.journaliser();
+    }
+
+    None
+}
+
+const DEFAULT_CONCURRENCE_ATTACHMENTS: usize = 4;
+const MAX_TENTATIVES_ATTACHMENT: u32 = 3;
+const DELAI_RETRY_ATTACHMENT_SECS: u64 = 5;
+
+/// Etat d'un attachment durant le traitement d'une commande pousserAttachment.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum EtatAttachment {
+    EnAttente,
+    EnCours,
+    Complete,
+    Echec,
+    Saute,
+}
+
+#[derive(Debug)]
+struct SuiviAttachment {
+    etat: EtatAttachment,
+    tentatives: u32,
+    /// Date du prochain essai d'un attachment en echec.
+    prochain_essai: Option<Instant>,
+}
+
+impl SuiviAttachment {
+    fn new() -> Self {
+        SuiviAttachment { etat: EtatAttachment::EnAttente, tentatives: 0, prochain_essai: None }
+    }
+}
+
+/// Attachments d'un message a pousser vers une millegrille tierce.
+#[derive(Debug)]
+pub struct DestinationAttachments {
+    commande: CommandePousserAttachments,
+    fiche: FicheMillegrilleApplication,
+    attachments: HashMap<String, SuiviAttachment>,
+    active: bool,
+}
+
+impl DestinationAttachments {
+    fn new(commande: CommandePousserAttachments, fiche: FicheMillegrilleApplication) -> Self {
+        DestinationAttachments { commande, fiche, attachments: HashMap::new(), active: true }
+    }
+
+    /// Delai restant avant de reessayer un fuuid en echec.
+    fn delai_retry(&self, fuuid: &str) -> Option<Duration> {
+        let prochain_essai = match self.attachments.get(fuuid) {
+            Some(a) if a.etat == EtatAttachment::Echec => a.prochain_essai?,
+            _ => return None
+        };
+        match prochain_essai.checked_duration_since(Instant::now()) {
+            Some(d) if d > Duration::from_secs(0) => Some(d),
+            _ => None
+        }
+    }
+
+    fn demarrer(&mut self, fuuid: &str) {
+        let suivi = self.attachments.entry(fuuid.into()).or_insert(SuiviAttachment::new());
+        suivi.etat = EtatAttachment::EnCours;
+        suivi.tentatives += 1;
+    }
+
+    /// Applique le code de l'evenement final de l'upload (None si aucun evenement n'a ete emis).
+    fn terminer(&mut self, fuuid: &str, code: Option<u32>) {
+        let suivi = match self.attachments.get_mut(fuuid) {
+            Some(s) => s,
+            None => return
+        };
+        suivi.etat = match code {
+            Some(CODE_UPLOAD_TERMINE) => EtatAttachment::Complete,
+            Some(CODE_UPLOAD_CORROMPU) | Some(CODE_UPLOAD_ANNULE) => EtatAttachment::Saute,
+            _ if suivi.tentatives < MAX_TENTATIVES_ATTACHMENT => EtatAttachment::Echec,
+            _ => EtatAttachment::Saute,
+        };
+        suivi.prochain_essai = match suivi.etat {
+            EtatAttachment::Echec => Some(Instant::now() + Duration::from_secs(DELAI_RETRY_ATTACHMENT_SECS * suivi.tentatives as u64)),
+            _ => None
+        };
+
+        if suivi.etat == EtatAttachment::Saute {
+            warn!("DestinationAttachments.terminer Attachment {} vers {} saute apres {} tentatives", fuuid, self.fiche.idmg, suivi.tentatives);
+        }
+    }
+
+    fn fuuids_sautes(&self) -> Vec<String> {
+        self.attachments.iter()
+            .filter(|(_, a)| a.etat == EtatAttachment::Saute)
+            .map(|(f, _)| f.clone())
+            .collect()
+    }
+
+    fn journaliser(&self) {
+        let completes = self.attachments.values().filter(|a| a.etat == EtatAttachment::Complete).count();
+        info!("DestinationAttachments Message {} vers {} : {} attachments completes, sautes : {:?}",
+            self.commande.uuid_message, self.fiche.idmg, completes, self.fuuids_sautes());
+    }
+}
+
+/// Emet un evenement d'erreur avec retry_after pour chaque attachment restant de la destination,
+/// Messagerie repousse la commande pousserAttachment apres le delai.
+async fn reporter_attachments_restants<M>(middleware: &M, destination: &DestinationAttachments, retry_after: u32)
+    where M: GenerateurMessages + VerificateurMessage + ValidateurX509
+{
+    let idmg = destination.fiche.idmg.as_str();
+    let uuid_message = destination.commande.uuid_message.as_str();
+
+    let mut exclus = destination.fuuids_sautes();
+    let mut reportes = Vec::new();
+    loop {
+        let fuuid = match get_prochain_attachment(middleware, &destination.commande, exclus.clone()).await {
+            Ok(r) if r.ok => match r.fuuid {
+                Some(f) => f,
+                None => break
+            },
+            Ok(_) => break,
+            Err(e) => {
+                error!("reporter_attachments_restants Erreur requete prochain attachment pour {} : {:?}", idmg, e);
                 break
             }
+        };
+        if exclus.contains(&fuuid) {
+            break  // Evite de boucler si Messagerie ignore les exclusions
         }
+
+        let mut evenement = EvenementUploadAttachment::erreur(uuid_message.into(), idmg.into(), fuuid.clone(), 503);
+        evenement.retry_after = Some(retry_after);
+        if let Err(e) = emettre_evenement_upload(middleware, evenement).await {
+            error!("reporter_attachments_restants Erreur emission evenement {} : {:?}", fuuid, e);
+            break
+        }
+        exclus.push(fuuid.clone());
+        reportes.push(fuuid);
     }
 
-    Ok(None)
+    info!("reporter_attachments_restants Message {} vers {} : attachments reportes de {} secondes : {:?}",
+        uuid_message, idmg, retry_after, reportes);
+}
+
+/// Demande a Messagerie le prochain fuuid a pousser vers la destination. Retourne None lorsqu'il
+/// ne reste rien a faire (aucun fuuid, ok=false, delai demande ou fuuid deja traite).
+async fn prochain_fuuid<M>(middleware: &M, gestionnaire: &GestionnairePostmaster, destination: &mut DestinationAttachments)
+    -> Option<String>
+    where M: GenerateurMessages + VerificateurMessage + ValidateurX509
+{
+    let idmg = destination.fiche.idmg.as_str();
+    if gestionnaire.annulations.est_annule(destination.commande.uuid_message.as_str(), Some(idmg), None) {
+        info!("prochain_fuuid Transmission du message {} vers {} annulee, on termine", destination.commande.uuid_message, idmg);
+        return None
+    }
+
+    if gestionnaire.idmgs_suspendus.est_suspendu(idmg) {
+        // Messagerie repoussera les attachments apres le delai, ils seront transmis une fois l'idmg repris
+        info!("prochain_fuuid Transmissions vers {} suspendues, on termine", idmg);
+        reporter_attachments_restants(middleware, destination, DELAI_IDMG_SUSPENDU_SECS as u32).await;
+        return None
+    }
+
+    if let Some(date) = gestionnaire.delais_remote.get(idmg) {
+        // La millegrille distante a demande de ralentir : les attachments restants sont remis a
+        // Messagerie avec le delai plutot que d'attendre dans la queue d'attachments
+        let attente = (date - Utc::now()).num_seconds().max(1) as u32;
+        info!("prochain_fuuid Uploads vers {} reportes a {:?}, on termine", idmg, date);
+        reporter_attachments_restants(middleware, destination, attente).await;
+        return None
+    }
+
+    let exclus = destination.fuuids_sautes();
+    let prochain_attachment = match get_prochain_attachment(middleware, &destination.commande, exclus).await {
+        Ok(r) => r,
+        Err(e) => {
+            error!("prochain_fuuid Erreur requete prochain attachment pour {}, on termine : {:?}", idmg, e);
+            return None
+        }
+    };
+
+    if ! prochain_attachment.ok {
+        debug!("prochain_fuuid Reponse prochain attachement ok=false pour {}, on termine", idmg);
+        return None
+    }
+
+    let fuuid = match prochain_attachment.fuuid {
+        Some(f) => f,
+        None => {
+            debug!("prochain_fuuid Aucun fuuid recu pour {}, on termine", idmg);
+            return None
+        }
+    };
+
+    match destination.attachments.get(fuuid.as_str()).map(|a| a.etat) {
+        None | Some(EtatAttachment::EnAttente) | Some(EtatAttachment::Echec) => Some(fuuid),
+        Some(etat) => {
+            // Messagerie retourne un fuuid deja traite, evite de boucler sur le meme fichier
+            warn!("prochain_fuuid Fuuid {} vers {} deja traite ({:?}), on termine", fuuid, idmg, etat);
+            None
+        }
+    }
 }
 
-async fn get_fiche<M>(middleware: &M, message_poster: &CommandePousserAttachments)
+/// Confirme chaque attachment d'un message local sans transfert.
+async fn confirmer_attachments_locaux<M>(middleware: &M, message_poster: &CommandePousserAttachments)
+    -> Result<(), Box<dyn Error>>
+    where M: GenerateurMessages + VerificateurMessage + ValidateurX509
+{
+    let uuid_message = message_poster.uuid_message.as_str();
+    let idmg = message_poster.idmg_destination.as_str();
+
+    let mut confirmes = HashSet::new();
+    loop {
+        let prochain_attachment = get_prochain_attachment(middleware, message_poster, Vec::new()).await?;
+        if ! prochain_attachment.ok {
+            debug!("confirmer_attachments_locaux Reponse prochain attachement ok=false, on termine");
+            break
+        }
+        let fuuid = match prochain_attachment.fuuid {
+            Some(f) => f,
+            None => break
+        };
+        if ! confirmes.insert(fuuid.clone()) {
+            // Messagerie n'a pas encore traite la confirmation precedente, evite de boucler
+            warn!("confirmer_attachments_locaux Fuuid {} deja confirme pour {}, on termine", fuuid, uuid_message);
+            break
+        }
+
+        debug!("confirmer_attachments_locaux Attachment local {}, aucun transfert requis", fuuid);
+        let evenement = EvenementUploadAttachment::complete(uuid_message.into(), idmg.into(), fuuid, 200);
+        emettre_evenement_upload(middleware, evenement).await?;
+    }
+
+    Ok(())
+}
+
+async fn get_fiche<M>(middleware: &M, gestionnaire: &GestionnairePostmaster, message_poster: &CommandePousserAttachments)
     -> Result<FicheMillegrilleApplication, Box<dyn Error>>
     where M: GenerateurMessages + VerificateurMessage + ValidateurX509
 {
     let idmg = message_poster.idmg_destination.as_str();
 
+    if let Some(fiche) = gestionnaire.cache_fiches.get(idmg, APPLICATION_MESSAGERIE) {
+        return Ok(fiche)
+    }
+
     let routage_topologie = RoutageMessageAction::builder(
         DOMAINE_TOPOLOGIE, REQUETE_APPLICATIONS_TIERS)
         .build();
-    let requete_topologie = RequeteTopologieFicheApplication { idmgs: vec![idmg.into()], application: "messagerie".into() };
+    let requete_topologie = RequeteTopologieFicheApplication { idmgs: vec![idmg.into()], application: APPLICATION_MESSAGERIE.into() };
     let reponse_topologie = middleware.transmettre_requete(routage_topologie, &requete_topologie).await?;
 
     debug!("get_fiche Reponse fiche topologie : {:?}", reponse_topologie);
@@ -231,23 +1237,82 @@ async fn get_fiche<M>(middleware: &M, message_poster: &CommandePousserAttachment
 
     let reponse_mappee: ReponseFichesApplication = reponse_topologie.message.parsed.map_contenu(None)?;
 
-    if reponse_mappee.fiches.len() == 1 {
-        // Retourner la fiche
-        if let Some(r) = reponse_mappee.fiches.into_iter().next() {
-            return Ok(r)
+    match selectionner_fiche(idmg, reponse_mappee.fiches) {
+        Some(r) => {
+            gestionnaire.cache_fiches.inserer(APPLICATION_MESSAGERIE, r.clone());
+            Ok(r)
+        },
+        None => Err(format!("commandes.get_fiche Aucune fiche trouve pour l'application messagerie sur {}", idmg))?
+    }
+}
+
+/// Combine les fiches recues pour l'idmg. La fiche la plus recente (version d'application) sert de base,
+/// les URLs d'application et adresses des autres fiches sont ajoutees a la suite.
+fn selectionner_fiche(idmg: &str, fiches: Vec<FicheMillegrilleApplication>) -> Option<FicheMillegrilleApplication> {
+    let mut fiches: Vec<FicheMillegrilleApplication> = fiches.into_iter()
+        .filter(|f| f.idmg.as_str() == idmg)
+        .collect();
+    if fiches.len() > 1 {
+        debug!("selectionner_fiche {} fiches recues pour {}", fiches.len(), idmg);
+    }
+
+    fiches.sort_by(|a, b| comparer_versions(version_fiche(b), version_fiche(a)));
+
+    let mut fiches_iter = fiches.into_iter();
+    let mut fiche = fiches_iter.next()?;
+    for autre in fiches_iter {
+        for app in autre.application {
+            if ! fiche.application.iter().any(|a| a.url == app.url) {
+                fiche.application.push(app);
+            }
+        }
+        for adresse in autre.adresses {
+            if ! fiche.adresses.contains(&adresse) {
+                fiche.adresses.push(adresse);
+            }
+        }
+        if fiche.ca.is_none() {
+            fiche.ca = autre.ca;
         }
     }
-    Err(format!("commandes.get_fiche Aucune fiche trouve pour l'application messagerie sur {}", idmg))?
+
+    Some(fiche)
+}
+
+fn version_fiche(fiche: &FicheMillegrilleApplication) -> Option<&str> {
+    fiche.application.iter()
+        .filter_map(|a| a.version.as_ref().map(|v| v.as_str()))
+        .max_by(|a, b| comparer_versions(Some(a), Some(b)))
 }
 
-async fn get_prochain_attachment<M>(middleware: &M, message_poster: &CommandePousserAttachments)
+/// Compare des versions de type 2022.1.0, segment par segment.
+fn comparer_versions(a: Option<&str>, b: Option<&str>) -> Ordering {
+    match (a, b) {
+        (Some(a), Some(b)) => {
+            let segments_a = a.split('.').map(|s| s.parse::<u64>().unwrap_or(0));
+            let segments_b = b.split('.').map(|s| s.parse::<u64>().unwrap_or(0));
+            segments_a.cmp(segments_b)
+        },
+        (Some(_), None) => Ordering::Greater,
+        (None, Some(_)) => Ordering::Less,
+        (None, None) => Ordering::Equal,
+    }
+}
+
+async fn get_prochain_attachment<M>(middleware: &M, message_poster: &CommandePousserAttachments, fuuids_exclus: Vec<String>)
     -> Result<ReponseProchainAttachment, Box<dyn Error>>
     where M: GenerateurMessages + VerificateurMessage + ValidateurX509
 {
     let routage = RoutageMessageAction::builder(DOMAINE_MESSAGERIE, COMMANDE_PROCHAIN_ATTACHMENT)
         .build();
 
-    let reponse: ReponseProchainAttachment = match middleware.transmettre_commande(routage, message_poster, true).await? {
+    let commande = CommandeProchainAttachment {
+        uuid_message: message_poster.uuid_message.clone(),
+        idmg_destination: message_poster.idmg_destination.clone(),
+        fuuids_exclus,
+    };
+
+    let reponse: ReponseProchainAttachment = match middleware.transmettre_commande(routage, &commande, true).await? {
         Some(t) => match t {
             TypeMessage::Valide(m) => Ok(m.message.parsed.map_contenu(None)?),
             _ => Err(format!("commandes.get_prochain_attachment Mauvais type message en reponse"))
@@ -259,3 +1324,74 @@ async fn get_prochain_attachment<M>(middleware: &M, message_poster: &CommandePou
 
     Ok(reponse)
 }
+
+#[cfg(test)]
+mod test_selection_fiche {
+    use super::*;
+    use crate::test_setup::setup;
+
+    fn fiche(idmg: &str, apps: Vec<(&str, Option<&str>)>, adresses: Vec<&str>) -> FicheMillegrilleApplication {
+        FicheMillegrilleApplication {
+            idmg: idmg.into(),
+            adresses: adresses.into_iter().map(|a| a.to_owned()).collect(),
+            application: apps.into_iter().map(|(url, version)| FicheApplication {
+                application: "messagerie_web".into(),
+                url: url.into(),
+                version: version.map(|v| v.to_owned()),
+            }).collect(),
+            ca: None,
+            chiffrage: None,
+        }
+    }
+
+    #[test]
+    fn test_comparer_versions() {
+        setup("test_comparer_versions");
+        assert_eq!(Ordering::Greater, comparer_versions(Some("2022.10.0"), Some("2022.9.5")));
+        assert_eq!(Ordering::Equal, comparer_versions(Some("2022.1.0"), Some("2022.1.0")));
+        assert_eq!(Ordering::Less, comparer_versions(Some("2022.1"), Some("2022.1.0")));
+        assert_eq!(Ordering::Greater, comparer_versions(Some("2022.1.0"), None));
+        assert_eq!(Ordering::Less, comparer_versions(None, Some("2022.1.0")));
+        assert_eq!(Ordering::Equal, comparer_versions(None, None));
+        // Segment non numerique traite comme 0
+        assert_eq!(Ordering::Less, comparer_versions(Some("2022.beta"), Some("2022.1")));
+    }
+
+    #[test]
+    fn test_selectionner_fiche_versions_mixtes() {
+        setup("test_selectionner_fiche_versions_mixtes");
+        let fiches = vec![
+            fiche("IDMG1", vec![("https://a/messagerie", None)], vec!["a"]),
+            fiche("IDMG1", vec![("https://b/messagerie", Some("2022.2.0"))], vec!["b"]),
+            fiche("IDMG1", vec![("https://c/messagerie", Some("2022.10.1"))], vec!["c"]),
+            fiche("IDMG2", vec![("https://d/messagerie", Some("2023.1.0"))], vec!["d"]),
+        ];
+
+        let fiche = selectionner_fiche("IDMG1", fiches).expect("fiche");
+        let urls: Vec<&str> = fiche.application.iter().map(|a| a.url.as_str()).collect();
+        assert_eq!(vec!["https://c/messagerie", "https://b/messagerie", "https://a/messagerie"], urls);
+        assert_eq!(vec!["c", "b", "a"], fiche.adresses);
+    }
+
+    #[test]
+    fn test_selectionner_fiche_urls_dupliques() {
+        setup("test_selectionner_fiche_urls_dupliques");
+        let fiches = vec![
+            fiche("IDMG1", vec![("https://a/messagerie", Some("2022.1.0"))], vec!["a"]),
+            fiche("IDMG1", vec![("https://a/messagerie", Some("2022.2.0")), ("https://b/messagerie", None)], vec!["a", "b"]),
+        ];
+
+        let fiche = selectionner_fiche("IDMG1", fiches).expect("fiche");
+        let urls: Vec<&str> = fiche.application.iter().map(|a| a.url.as_str()).collect();
+        assert_eq!(vec!["https://a/messagerie", "https://b/messagerie"], urls);
+        assert_eq!(Some("2022.2.0"), fiche.application[0].version.as_ref().map(|v| v.as_str()));
+        assert_eq!(vec!["a", "b"], fiche.adresses);
+    }
+
+    #[test]
+    fn test_selectionner_fiche_aucune() {
+        setup("test_selectionner_fiche_aucune");
+        let fiches = vec![fiche("IDMG2", vec![("https://d/messagerie", None)], vec![])];
+        assert!(selectionner_fiche("IDMG1", fiches).is_none());
+    }
+}
@@ -0,0 +1,63 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+
+use log::debug;
+use millegrilles_common_rust::tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::config::lire_env;
+use crate::constantes::*;
+
+const DEFAULT_LIMITE_GLOBALE: usize = 10;
+const DEFAULT_LIMITE_IDMG: usize = 2;
+
+/// Limite le nombre de transmissions simultanees, globalement et par millegrille tierce.
+#[derive(Debug)]
+pub struct LimiteurConcurrence {
+    global: Arc<Semaphore>,
+    limite_idmg: usize,
+    par_idmg: Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+/// Permis de transmission, libere lorsqu'il est drop.
+pub struct PermisTransmission {
+    _idmg: OwnedSemaphorePermit,
+    _global: OwnedSemaphorePermit,
+}
+
+impl LimiteurConcurrence {
+    pub fn new(limite_globale: usize, limite_idmg: usize) -> Self {
+        LimiteurConcurrence {
+            global: Arc::new(Semaphore::new(limite_globale.max(1))),
+            limite_idmg: limite_idmg.max(1),
+            par_idmg: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn charger_env() -> Self {
+        let limite_globale = lire_env(ENV_CONCURRENCE_GLOBALE).unwrap_or(DEFAULT_LIMITE_GLOBALE);
+        let limite_idmg = lire_env(ENV_CONCURRENCE_IDMG).unwrap_or(DEFAULT_LIMITE_IDMG);
+        LimiteurConcurrence::new(limite_globale, limite_idmg)
+    }
+
+    /// Attend un permis pour l'idmg puis un permis global.
+    pub async fn acquerir(&self, idmg: &str) -> Result<PermisTransmission, Box<dyn Error>> {
+        let semaphore_idmg = {
+            let mut par_idmg = self.par_idmg.lock().expect("lock par_idmg");
+            match par_idmg.get(idmg) {
+                Some(s) => s.clone(),
+                None => {
+                    let s = Arc::new(Semaphore::new(self.limite_idmg));
+                    par_idmg.insert(idmg.into(), s.clone());
+                    s
+                }
+            }
+        };
+
+        let permis_idmg = semaphore_idmg.acquire_owned().await?;
+        let permis_global = self.global.clone().acquire_owned().await?;
+        debug!("LimiteurConcurrence.acquerir Permis obtenu pour {}", idmg);
+
+        Ok(PermisTransmission { _idmg: permis_idmg, _global: permis_global })
+    }
+}
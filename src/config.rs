@@ -0,0 +1,17 @@
+use std::env;
+use std::str::FromStr;
+
+use log::warn;
+
+/// Lit une valeur de configuration dans l'environnement. Retourne None si la variable est absente
+/// ou invalide (avec un avertissement).
+pub fn lire_env<T: FromStr>(nom: &str) -> Option<T> {
+    let valeur = env::var(nom).ok()?;
+    match valeur.parse::<T>() {
+        Ok(v) => Some(v),
+        Err(_) => {
+            warn!("config.lire_env Valeur invalide pour {} : {}", nom, valeur);
+            None
+        }
+    }
+}
@@ -3,17 +3,57 @@ pub const DOMAINE_TOPOLOGIE: &str = "CoreTopologie";
 pub const DOMAINE_MESSAGERIE: &str = "Messagerie";
 
 pub const REQUETE_APPLICATIONS_TIERS: &str = "applicationsTiers";
+pub const APPLICATION_MESSAGERIE: &str = "messagerie";
 
 pub const COMMANDE_POSTER: &str = "poster";
 pub const COMMANDE_POUSSER_ATTACHMENT: &str = "pousserAttachment";
+pub const COMMANDE_POUSSER_ATTACHMENT_DESTINATIONS: &str = "pousserAttachmentDestinations";
 pub const COMMANDE_PROCHAIN_ATTACHMENT: &str = "prochainAttachment";
+pub const COMMANDE_CONFIRMER_TRANSMISSION: &str = "confirmerTransmission";
+pub const COMMANDE_RECEVOIR: &str = "recevoir";
+pub const COMMANDE_REJOUER_NON_LIVRABLE: &str = "rejouerNonLivrable";
+pub const COMMANDE_ANNULER_TRANSMISSION: &str = "annulerTransmission";
+pub const COMMANDE_REESSAYER_MAINTENANT: &str = "reessayerMaintenant";
+pub const COMMANDE_PURGER_FILE: &str = "purgerFile";
+pub const COMMANDE_SUSPENDRE_IDMG: &str = "suspendreIdmg";
+pub const COMMANDE_REPRENDRE_IDMG: &str = "reprendreIdmg";
+
+pub const REQUETE_NON_LIVRABLES: &str = "nonLivrables";
+pub const REQUETE_ETAT_TRANSMISSION: &str = "etatTransmission";
+pub const REQUETE_LISTER_TRANSMISSIONS_EN_COURS: &str = "listerTransmissionsEnCours";
 
 pub const EVENEMENT_UPLOAD_ATTACHMENT: &str = "evenementAttachment";
+pub const EVENEMENT_NON_LIVRABLE: &str = "evenementNonLivrable";
+pub const EVENEMENT_FICHE_PUBLIQUE: &str = "fichePublique";
+pub const EVENEMENT_TRANSMISSION_ANNULEE: &str = "evenementTransmissionAnnulee";
 
 pub const NOM_Q_VOLATILS: &str = "postmaster/volatils";
 pub const NOM_Q_TRIGGERS: &str = "postmaster/triggers";
 
+pub const ENV_FILE_SORTANTE: &str = "MG_POSTMASTER_FILE_SORTANTE";
+pub const DEFAULT_REPERTOIRE_FILE_SORTANTE: &str = "/var/opt/millegrilles/postmaster/file_sortante";
+/// Sans extension .json pour ne pas etre lu comme une entree de la file sortante.
+pub const FICHIER_IDMGS_SUSPENDUS: &str = "idmgs_suspendus";
+pub const ENV_TLS_WEBPKI: &str = "MG_POSTMASTER_TLS_WEBPKI";
+pub const ENV_CONNECT_TIMEOUT: &str = "MG_POSTMASTER_CONNECT_TIMEOUT";
+pub const ENV_REQUEST_TIMEOUT: &str = "MG_POSTMASTER_REQUEST_TIMEOUT";
+pub const ENV_FICHE_TTL: &str = "MG_POSTMASTER_FICHE_TTL";
+pub const ENV_CONCURRENCE_GLOBALE: &str = "MG_POSTMASTER_CONCURRENCE";
+pub const ENV_CONCURRENCE_IDMG: &str = "MG_POSTMASTER_CONCURRENCE_IDMG";
+pub const ENV_CONCURRENCE_ATTACHMENTS: &str = "MG_POSTMASTER_CONCURRENCE_ATTACHMENTS";
+pub const ENV_DEBIT_GLOBAL: &str = "MG_POSTMASTER_DEBIT";
+pub const ENV_DEBIT_IDMG: &str = "MG_POSTMASTER_DEBIT_IDMG";
+pub const ENV_DEBIT_FENETRE: &str = "MG_POSTMASTER_DEBIT_FENETRE";
+pub const ENV_REPERTOIRE_ATTACHMENTS: &str = "MG_POSTMASTER_REPERTOIRE_ATTACHMENTS";
+pub const ENV_REPERTOIRE_DEBORDEMENT: &str = "MG_POSTMASTER_REPERTOIRE_DEBORDEMENT";
+pub const DEFAULT_REPERTOIRE_DEBORDEMENT: &str = "/var/opt/millegrilles/postmaster/debordement";
+
 pub const CODE_UPLOAD_DEBUT: u32 = 1;
 pub const CODE_UPLOAD_ENCOURS: u32 = 2;
 pub const CODE_UPLOAD_TERMINE: u32 = 3;
 pub const CODE_UPLOAD_ERREUR: u32 = 4;
+pub const CODE_UPLOAD_CORROMPU: u32 = 5;
+pub const CODE_UPLOAD_ANNULE: u32 = 6;
+
+/// Code de reponse de la millegrille distante lorsque le fichier est deja present.
+pub const CODE_FICHIER_EXISTANT: u32 = 7;
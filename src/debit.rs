@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use log::{debug, warn};
+use millegrilles_common_rust::chrono::{Timelike, Utc};
+use millegrilles_common_rust::tokio::time::{Duration, sleep};
+
+use crate::config::lire_env;
+use crate::constantes::*;
+
+/// Seau a jetons (bytes). Les jetons peuvent devenir negatifs : l'appelant attend que la dette soit
+/// remboursee, ce qui conserve le debit moyen meme avec plusieurs uploads simultanes.
+#[derive(Debug)]
+struct SeauJetons {
+    debit: f64,
+    etat: Mutex<EtatSeau>,
+}
+
+#[derive(Debug)]
+struct EtatSeau {
+    jetons: f64,
+    derniere_maj: Instant,
+}
+
+impl SeauJetons {
+    fn new(debit: u64) -> Self {
+        let debit = debit.max(1) as f64;
+        SeauJetons {
+            debit,
+            etat: Mutex::new(EtatSeau { jetons: debit, derniere_maj: Instant::now() }),
+        }
+    }
+
+    /// Retire les bytes du seau et retourne l'attente requise avant de les transmettre.
+    fn consommer(&self, bytes: usize) -> Duration {
+        let mut etat = self.etat.lock().expect("lock seau");
+        let maintenant = Instant::now();
+        let ecoule = maintenant.duration_since(etat.derniere_maj).as_secs_f64();
+        // Capacite d'une seconde de debit
+        etat.jetons = (etat.jetons + ecoule * self.debit).min(self.debit);
+        etat.derniere_maj = maintenant;
+        etat.jetons -= bytes as f64;
+
+        match etat.jetons < 0.0 {
+            true => Duration::from_secs_f64(-etat.jetons / self.debit),
+            false => Duration::from_secs(0)
+        }
+    }
+}
+
+/// Plage horaire (heures UTC) durant laquelle les transferts d'attachments ne sont pas limites.
+#[derive(Clone, Copy, Debug)]
+struct FenetreHoraire {
+    debut: u32,
+    fin: u32,
+}
+
+impl FenetreHoraire {
+    /// Format "debut-fin", e.g. "22-6".
+    fn parse(valeur: &str) -> Option<Self> {
+        let (debut, fin) = valeur.split_once('-')?;
+        let debut = debut.trim().parse::<u32>().ok()?;
+        let fin = fin.trim().parse::<u32>().ok()?;
+        match debut < 24 && fin < 24 {
+            true => Some(FenetreHoraire { debut, fin }),
+            false => None
+        }
+    }
+
+    fn contient(&self, heure: u32) -> bool {
+        match self.debut <= self.fin {
+            true => heure >= self.debut && heure < self.fin,
+            false => heure >= self.debut || heure < self.fin  // Passe minuit
+        }
+    }
+}
+
+/// Limite le debit (bytes/sec) des uploads d'attachments, globalement et par millegrille tierce.
+/// Les uploads sont executes par la queue d'attachments : les messages (poster) ne passent pas par
+/// ce limiteur et leur Q de commandes n'attend jamais apres un upload ralenti.
+#[derive(Debug)]
+pub struct LimiteurDebit {
+    global: Option<SeauJetons>,
+    debit_idmg: Option<u64>,
+    par_idmg: Mutex<HashMap<String, Arc<SeauJetons>>>,
+    fenetre_libre: Option<FenetreHoraire>,
+}
+
+impl LimiteurDebit {
+    pub fn new(debit_global: Option<u64>, debit_idmg: Option<u64>) -> Self {
+        LimiteurDebit {
+            global: debit_global.map(SeauJetons::new),
+            debit_idmg,
+            par_idmg: Mutex::new(HashMap::new()),
+            fenetre_libre: None,
+        }
+    }
+
+    pub fn charger_env() -> Self {
+        let mut limiteur = LimiteurDebit::new(lire_env(ENV_DEBIT_GLOBAL), lire_env(ENV_DEBIT_IDMG));
+        if let Some(v) = lire_env::<String>(ENV_DEBIT_FENETRE) {
+            match FenetreHoraire::parse(v.as_str()) {
+                Some(f) => limiteur.fenetre_libre = Some(f),
+                None => warn!("LimiteurDebit.charger_env Fenetre horaire invalide : {}", v)
+            }
+        }
+        debug!("LimiteurDebit.charger_env {:?}", limiteur);
+        limiteur
+    }
+
+    /// Attend que les bytes puissent etre transmis vers l'idmg.
+    pub async fn consommer(&self, idmg: &str, bytes: usize) {
+        if let Some(f) = self.fenetre_libre.as_ref() {
+            if f.contient(Utc::now().hour()) {
+                return
+            }
+        }
+
+        let mut attente = Duration::from_secs(0);
+        if let Some(seau) = self.global.as_ref() {
+            attente = attente.max(seau.consommer(bytes));
+        }
+        if let Some(seau) = self.get_seau_idmg(idmg) {
+            attente = attente.max(seau.consommer(bytes));
+        }
+
+        if attente > Duration::from_secs(0) {
+            sleep(attente).await;
+        }
+    }
+
+    fn get_seau_idmg(&self, idmg: &str) -> Option<Arc<SeauJetons>> {
+        let debit = self.debit_idmg?;
+        let mut par_idmg = self.par_idmg.lock().expect("lock par_idmg");
+        let seau = par_idmg.entry(idmg.into()).or_insert_with(|| Arc::new(SeauJetons::new(debit)));
+        Some(seau.clone())
+    }
+}
@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::sync::Mutex;
+
+use log::info;
+use millegrilles_common_rust::chrono::{DateTime, Duration, Utc};
+use millegrilles_common_rust::reqwest::StatusCode;
+use millegrilles_common_rust::reqwest::header::{HeaderMap, RETRY_AFTER};
+
+const DEFAULT_RETRY_AFTER_SECS: u32 = 60;
+const MAX_RETRY_AFTER_SECS: u32 = 6 * 3600;
+
+/// Delais demandes par les millegrilles tierces (429/503 avec Retry-After), par idmg.
+#[derive(Debug)]
+pub struct DelaisRemote {
+    delais: Mutex<HashMap<String, DateTime<Utc>>>,
+}
+
+impl DelaisRemote {
+    pub fn new() -> Self {
+        DelaisRemote { delais: Mutex::new(HashMap::new()) }
+    }
+
+    /// Reporte les transmissions vers l'idmg. Un delai plus court que le delai courant est ignore.
+    pub fn reporter(&self, idmg: &str, secondes: u32) {
+        let date = Utc::now() + Duration::seconds(secondes as i64);
+        let mut delais = self.delais.lock().expect("lock delais");
+        match delais.get(idmg) {
+            Some(d) if *d >= date => (),
+            _ => {
+                info!("DelaisRemote.reporter Transmissions vers {} reportees de {} secondes", idmg, secondes);
+                delais.insert(idmg.into(), date);
+            }
+        }
+    }
+
+    /// Retire le delai de l'idmg (retry force par un administrateur).
+    pub fn retirer(&self, idmg: &str) {
+        self.delais.lock().expect("lock delais").remove(idmg);
+    }
+
+    /// Retourne la date avant laquelle il ne faut pas transmettre vers l'idmg.
+    pub fn get(&self, idmg: &str) -> Option<DateTime<Utc>> {
+        let mut delais = self.delais.lock().expect("lock delais");
+        match delais.get(idmg) {
+            Some(d) if *d > Utc::now() => Some(*d),
+            Some(_) => {
+                delais.remove(idmg);
+                None
+            },
+            None => None
+        }
+    }
+}
+
+/// La millegrille distante demande de ralentir (429 ou 503).
+#[derive(Debug)]
+pub struct ErreurRetryAfter {
+    pub status: u16,
+    pub retry_after: u32,
+}
+
+impl fmt::Display for ErreurRetryAfter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Millegrille distante occupee (http status {}), retry dans {} secondes", self.status, self.retry_after)
+    }
+}
+
+impl Error for ErreurRetryAfter {}
+
+pub fn est_status_ralentir(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status == StatusCode::SERVICE_UNAVAILABLE
+}
+
+/// Lit l'entete Retry-After (secondes ou date http). Retourne le delai par defaut si l'entete est
+/// absente ou invalide.
+pub fn lire_retry_after(headers: &HeaderMap) -> u32 {
+    let valeur = match headers.get(RETRY_AFTER) {
+        Some(v) => match v.to_str() {
+            Ok(v) => v.trim(),
+            Err(_) => return DEFAULT_RETRY_AFTER_SECS
+        },
+        None => return DEFAULT_RETRY_AFTER_SECS
+    };
+
+    let secondes = match valeur.parse::<u32>() {
+        Ok(s) => s,
+        Err(_) => match DateTime::parse_from_rfc2822(valeur) {
+            Ok(date) => (date.with_timezone(&Utc) - Utc::now()).num_seconds().clamp(0, MAX_RETRY_AFTER_SECS as i64) as u32,
+            Err(_) => DEFAULT_RETRY_AFTER_SECS
+        }
+    };
+
+    secondes.min(MAX_RETRY_AFTER_SECS)
+}
+
+#[cfg(test)]
+mod test_retry_after {
+    use super::*;
+    use millegrilles_common_rust::reqwest::header::HeaderValue;
+    use crate::test_setup::setup;
+
+    fn headers(valeur: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from_str(valeur).expect("header"));
+        headers
+    }
+
+    #[test]
+    fn test_retry_after_secondes() {
+        setup("test_retry_after_secondes");
+        assert_eq!(120, lire_retry_after(&headers("120")));
+        assert_eq!(0, lire_retry_after(&headers("0")));
+        assert_eq!(DEFAULT_RETRY_AFTER_SECS, lire_retry_after(&HeaderMap::new()));
+        assert_eq!(DEFAULT_RETRY_AFTER_SECS, lire_retry_after(&headers("bientot")));
+        assert_eq!(DEFAULT_RETRY_AFTER_SECS, lire_retry_after(&headers("-5")));
+    }
+
+    #[test]
+    fn test_retry_after_date_http() {
+        setup("test_retry_after_date_http");
+        let date = (Utc::now() + Duration::seconds(300)).to_rfc2822();
+        let secondes = lire_retry_after(&headers(date.as_str()));
+        assert!(secondes >= 298 && secondes <= 300, "secondes : {}", secondes);
+
+        // Format IMF-fixdate (GMT), date passee
+        assert_eq!(0, lire_retry_after(&headers("Wed, 21 Oct 2015 07:28:00 GMT")));
+    }
+
+    #[test]
+    fn test_retry_after_maximum() {
+        setup("test_retry_after_maximum");
+        assert_eq!(MAX_RETRY_AFTER_SECS, lire_retry_after(&headers("86400")));
+        assert_eq!(MAX_RETRY_AFTER_SECS, lire_retry_after(&headers("4294967295")));
+
+        let date = (Utc::now() + Duration::days(2)).to_rfc2822();
+        assert_eq!(MAX_RETRY_AFTER_SECS, lire_retry_after(&headers(date.as_str())));
+        // Date au-dela de la plage d'un u32
+        assert_eq!(MAX_RETRY_AFTER_SECS, lire_retry_after(&headers("Mon, 01 Jan 2300 00:00:00 GMT")));
+    }
+}
@@ -0,0 +1,80 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use log::{debug, warn};
+use millegrilles_common_rust::reqwest::Url;
+
+use crate::messages_struct::*;
+
+const PATH_APPLICATION_DEFAUT: &str = "/messagerie";
+
+/// Conserve l'URL qui a fonctionne pour chaque millegrille tierce.
+#[derive(Debug)]
+pub struct EndpointsRemote {
+    preferes: Mutex<HashMap<String, String>>,
+}
+
+impl EndpointsRemote {
+    pub fn new() -> Self {
+        EndpointsRemote { preferes: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn get_prefere(&self, idmg: &str) -> Option<String> {
+        self.preferes.lock().expect("lock preferes").get(idmg).cloned()
+    }
+
+    pub fn set_prefere(&self, idmg: &str, url: &str) {
+        let mut preferes = self.preferes.lock().expect("lock preferes");
+        if preferes.get(idmg).map(|u| u.as_str()) != Some(url) {
+            debug!("EndpointsRemote.set_prefere {} -> {}", idmg, url);
+            preferes.insert(idmg.into(), url.into());
+        }
+    }
+
+    /// Liste ordonnee des URLs d'application a essayer : l'URL preferee, les URLs de la fiche,
+    /// puis celles construites a partir des adresses de la fiche et des noms DNS du mapping.
+    pub fn candidats(&self, fiche: &FicheMillegrilleApplication, mapping: Option<&DocMappingIdmg>) -> Vec<String> {
+        let mut urls: Vec<String> = Vec::new();
+
+        if let Some(u) = self.get_prefere(fiche.idmg.as_str()) {
+            ajouter_candidat(&mut urls, u.as_str());
+        }
+
+        for app in &fiche.application {
+            ajouter_candidat(&mut urls, app.url.as_str());
+        }
+
+        let path_application = match fiche.application.first() {
+            Some(app) => match Url::parse(app.url.as_str()) {
+                Ok(u) => u.path().trim_end_matches('/').to_owned(),
+                Err(e) => {
+                    warn!("EndpointsRemote.candidats URL d'application invalide {} : {:?}", app.url, e);
+                    PATH_APPLICATION_DEFAUT.into()
+                }
+            },
+            None => PATH_APPLICATION_DEFAUT.into()
+        };
+
+        let dns = match mapping {
+            Some(m) => m.dns.as_ref(),
+            None => None
+        };
+        let hotes = fiche.adresses.iter().chain(dns.into_iter().flatten());
+        for hote in hotes {
+            let url = match hote.contains("://") {
+                true => format!("{}{}", hote.trim_end_matches('/'), path_application),
+                false => format!("https://{}{}", hote, path_application),
+            };
+            ajouter_candidat(&mut urls, url.as_str());
+        }
+
+        urls
+    }
+}
+
+fn ajouter_candidat(urls: &mut Vec<String>, url: &str) {
+    let url = url.trim_end_matches('/');
+    if ! urls.iter().any(|u| u.as_str() == url) {
+        urls.push(url.into());
+    }
+}
@@ -12,6 +12,7 @@ use millegrilles_common_rust::tokio_stream::StreamExt;
 
 use crate::constantes::*;
 use crate::gestionnaire::GestionnairePostmaster;
+use crate::messages_struct::EvenementFicheMillegrille;
 
 pub async fn consommer_evenement<M>(gestionnaire: &GestionnairePostmaster, middleware: &M, m: MessageValideAction)
     -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
@@ -26,7 +27,22 @@ pub async fn consommer_evenement<M>(gestionnaire: &GestionnairePostmaster, middl
     }?;
 
     match m.action.as_str() {
-        // EVENEMENT_POMPE_POSTE => evenement_pompe_poste(gestionnaire, middleware, &m).await,
+        EVENEMENT_FICHE_PUBLIQUE => evenement_fiche_publique(gestionnaire, &m).await,
         _ => Err(format!("gestionnaire.consommer_transaction: Mauvais type d'action pour une transaction : {}", m.action))?,
     }
 }
+
+/// Une fiche a ete modifiee sur CoreTopologie, on retire la millegrille du cache.
+async fn evenement_fiche_publique(gestionnaire: &GestionnairePostmaster, m: &MessageValideAction)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+{
+    match m.message.parsed.map_contenu::<EvenementFicheMillegrille>(None) {
+        Ok(e) => gestionnaire.cache_fiches.invalider(e.idmg.as_str()),
+        Err(e) => {
+            warn!("evenement_fiche_publique Evenement sans idmg ({:?}), on vide le cache", e);
+            gestionnaire.cache_fiches.invalider_tout();
+        }
+    }
+
+    Ok(None)
+}
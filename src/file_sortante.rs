@@ -0,0 +1,356 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use log::{debug, error, info, warn};
+use millegrilles_common_rust::chiffrage_cle::MetaInformationCle;
+use millegrilles_common_rust::chrono::{Duration, Utc};
+use millegrilles_common_rust::formatteur_messages::DateEpochSeconds;
+use millegrilles_common_rust::serde::{Deserialize, Serialize};
+use millegrilles_common_rust::serde_json;
+use millegrilles_common_rust::serde_json::{Map, Value};
+
+use crate::messages_struct::*;
+
+const EXTENSION_ENTREE: &str = "json";
+const REPERTOIRE_NON_LIVRABLES: &str = "non_livrables";
+const DEFAULT_NOMBRE_RETRY: u32 = 10;
+const DELAI_RETRY_BASE_SECS: i64 = 30;
+const DELAI_RETRY_MAX_SECS: i64 = 6 * 3600;
+
+/// Transmission d'un message vers une millegrille tierce (une entree par idmg de destination).
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EntreeFileSortante {
+    pub uuid_message: String,
+    pub message: Map<String, Value>,
+    pub cle_info: MetaInformationCle,
+    pub certificat_message: Vec<String>,
+    pub certificat_millegrille: String,
+    pub destination: IdmgMappingDestinataires,
+    pub tentatives: u32,
+    pub date_creation: DateEpochSeconds,
+    pub prochain_essai: DateEpochSeconds,
+    pub dernier_code: Option<u16>,
+    pub confirmation: Option<ConfirmationTransmission>,
+    #[serde(default)]
+    pub historique: Vec<TentativeTransmission>,
+    #[serde(default)]
+    pub non_livrable: bool,
+    pub retry_after: Option<u32>,
+}
+
+impl EntreeFileSortante {
+    pub fn new(uuid_message: &str, commande: &CommandePostmasterPoster, destination: IdmgMappingDestinataires) -> Self {
+        EntreeFileSortante {
+            uuid_message: uuid_message.into(),
+            message: commande.message.clone(),
+            cle_info: commande.cle_info.clone(),
+            certificat_message: commande.certificat_message.clone(),
+            certificat_millegrille: commande.certificat_millegrille.clone(),
+            destination,
+            tentatives: 0,
+            date_creation: DateEpochSeconds::now(),
+            prochain_essai: DateEpochSeconds::now(),
+            dernier_code: None,
+            confirmation: None,
+            historique: Vec::new(),
+            non_livrable: false,
+            retry_after: None,
+        }
+    }
+
+    /// Cle unique de l'entree, sert aussi de nom de fichier.
+    pub fn cle(&self) -> String {
+        format!("{}_{}", self.uuid_message, self.destination.idmg)
+    }
+
+    /// Nombre de retry permis apres la premiere tentative (DocMappingIdmg.retry).
+    pub fn nombre_retry(&self) -> u32 {
+        self.destination.mapping.retry.unwrap_or(DEFAULT_NOMBRE_RETRY)
+    }
+
+    pub fn retry_epuises(&self) -> bool {
+        self.tentatives > self.nombre_retry()
+    }
+
+    pub fn ajouter_historique(&mut self, code: u16, raison: Option<String>) {
+        self.dernier_code = Some(code);
+        self.historique.push(TentativeTransmission { date: DateEpochSeconds::now(), code, raison });
+    }
+
+    /// Planifie le prochain essai avec un backoff exponentiel, sans devancer le Retry-After
+    /// demande par la millegrille distante.
+    pub fn planifier_retry(&mut self) {
+        let exposant = match self.tentatives { 0 => 0, t => (t - 1).min(16) };
+        let mut delai = (DELAI_RETRY_BASE_SECS << exposant).min(DELAI_RETRY_MAX_SECS);
+        if let Some(r) = self.retry_after {
+            delai = delai.max(r as i64);
+        }
+        self.prochain_essai = DateEpochSeconds::from(Utc::now() + Duration::seconds(delai));
+    }
+
+    /// Reporte le prochain essai sans compter de tentative.
+    pub fn reporter(&mut self, secondes: i64) {
+        self.prochain_essai = DateEpochSeconds::from(Utc::now() + Duration::seconds(secondes));
+    }
+}
+
+/// File persistante (un fichier json par entree) des messages a transmettre.
+#[derive(Debug)]
+pub struct FileSortante {
+    repertoire: PathBuf,
+    /// False si la file n'a pas pu etre chargee : les nouveaux messages sont refuses plutot que
+    /// conserves en memoire (ils seraient perdus au redemarrage).
+    disponible: AtomicBool,
+    entrees: Mutex<HashMap<String, EntreeFileSortante>>,
+    en_cours: Mutex<HashSet<String>>,
+}
+
+impl FileSortante {
+    pub fn new<P: Into<PathBuf>>(repertoire: P) -> Self {
+        FileSortante {
+            repertoire: repertoire.into(),
+            disponible: AtomicBool::new(true),
+            entrees: Mutex::new(HashMap::new()),
+            en_cours: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Charge les entrees conservees sur disque (e.g. apres un redemarrage).
+    pub fn charger(&self) -> Result<usize, Box<dyn Error>> {
+        fs::create_dir_all(self.repertoire.join(REPERTOIRE_NON_LIVRABLES))?;
+
+        let mut entrees = self.entrees.lock().expect("lock entrees");
+        for entree in lire_entrees(&self.repertoire)? {
+            entrees.insert(entree.cle(), entree);
+        }
+
+        info!("FileSortante.charger {} entrees chargees de {:?}", entrees.len(), self.repertoire);
+        Ok(entrees.len())
+    }
+
+    /// Refuse les nouvelles entrees (chargement de la file en erreur).
+    pub fn marquer_indisponible(&self) {
+        self.disponible.store(false, Ordering::Relaxed);
+    }
+
+    /// Ajoute une nouvelle entree. L'entree est reservee pour l'appelant qui doit la liberer.
+    pub fn ajouter(&self, entree: &EntreeFileSortante) -> Result<(), Box<dyn Error>> {
+        if ! self.disponible.load(Ordering::Relaxed) {
+            Err(format!("file_sortante.ajouter File sortante indisponible (repertoire {:?}), entree {} refusee", self.repertoire, entree.cle()))?
+        }
+        let cle = entree.cle();
+        self.en_cours.lock().expect("lock en_cours").insert(cle.clone());
+        if let Err(e) = self.sauvegarder(entree) {
+            self.en_cours.lock().expect("lock en_cours").remove(&cle);
+            Err(e)?
+        }
+        Ok(())
+    }
+
+    pub fn sauvegarder(&self, entree: &EntreeFileSortante) -> Result<(), Box<dyn Error>> {
+        ecrire_entree(&self.repertoire, entree)?;
+        debug!("FileSortante.sauvegarder Entree {} sauvegardee", entree.cle());
+        self.entrees.lock().expect("lock entrees").insert(entree.cle(), entree.clone());
+        Ok(())
+    }
+
+    pub fn retirer(&self, cle: &str) -> Result<(), Box<dyn Error>> {
+        self.entrees.lock().expect("lock entrees").remove(cle);
+        supprimer_entree(&self.repertoire, cle)
+    }
+
+    /// Retire les entrees du message qui ne sont pas en cours de traitement (toutes les destinations
+    /// si idmg est None). Retourne les idmgs retires.
+    pub fn retirer_message(&self, uuid_message: &str, idmg: Option<&str>) -> Result<Vec<String>, Box<dyn Error>> {
+        let retirees = self.retirer_entrees(Some(uuid_message), idmg)?;
+        Ok(retirees.into_iter().map(|e| e.destination.idmg).collect())
+    }
+
+    /// Retire les entrees (filtrees par message et/ou idmg) qui ne sont pas en cours de traitement.
+    pub fn retirer_entrees(&self, uuid_message: Option<&str>, idmg: Option<&str>) -> Result<Vec<EntreeFileSortante>, Box<dyn Error>> {
+        let retirees: Vec<EntreeFileSortante> = {
+            let entrees = self.entrees.lock().expect("lock entrees");
+            let en_cours = self.en_cours.lock().expect("lock en_cours");
+            entrees.iter()
+                .filter(|(cle, e)| ! en_cours.contains(*cle) && correspond(e, uuid_message, idmg))
+                .map(|(_, e)| e.clone())
+                .collect()
+        };
+
+        for entree in &retirees {
+            self.retirer(entree.cle().as_str())?;
+        }
+
+        Ok(retirees)
+    }
+
+    /// Supprime les non livrables (filtres par message et/ou idmg). Retourne le nombre supprime.
+    pub fn purger_non_livrables(&self, uuid_message: Option<&str>, idmg: Option<&str>) -> Result<usize, Box<dyn Error>> {
+        let repertoire_non_livrables = self.repertoire.join(REPERTOIRE_NON_LIVRABLES);
+        let mut compteur = 0;
+        for entree in lire_entrees(&repertoire_non_livrables)? {
+            if correspond(&entree, uuid_message, idmg) {
+                supprimer_entree(&repertoire_non_livrables, entree.cle().as_str())?;
+                compteur += 1;
+            }
+        }
+        Ok(compteur)
+    }
+
+    /// Devance le prochain essai des entrees (filtrees par message et/ou idmg) qui ne sont pas en cours
+    /// de traitement. Retourne les entrees modifiees.
+    pub fn reessayer_maintenant(&self, uuid_message: Option<&str>, idmg: Option<&str>) -> Result<Vec<EntreeFileSortante>, Box<dyn Error>> {
+        let entrees: Vec<EntreeFileSortante> = {
+            let entrees = self.entrees.lock().expect("lock entrees");
+            let en_cours = self.en_cours.lock().expect("lock en_cours");
+            entrees.iter()
+                .filter(|(cle, e)| ! en_cours.contains(*cle) && correspond(e, uuid_message, idmg))
+                .map(|(_, e)| e.clone())
+                .collect()
+        };
+
+        let mut modifiees = Vec::new();
+        for mut entree in entrees {
+            entree.prochain_essai = DateEpochSeconds::now();
+            entree.retry_after = None;
+            self.sauvegarder(&entree)?;
+            modifiees.push(entree);
+        }
+
+        Ok(modifiees)
+    }
+
+    /// Deplace une entree vers les non livrables (dead letter) pour inspection et replay.
+    pub fn deplacer_non_livrable(&self, entree: &EntreeFileSortante) -> Result<(), Box<dyn Error>> {
+        ecrire_entree(&self.repertoire.join(REPERTOIRE_NON_LIVRABLES), entree)?;
+        self.retirer(entree.cle().as_str())
+    }
+
+    pub fn lister_non_livrables(&self) -> Result<Vec<EntreeFileSortante>, Box<dyn Error>> {
+        lire_entrees(&self.repertoire.join(REPERTOIRE_NON_LIVRABLES))
+    }
+
+    /// Retire une entree des non livrables et la remet dans la file (reservee pour l'appelant).
+    pub fn rejouer_non_livrable(&self, uuid_message: &str, idmg: &str) -> Result<Option<EntreeFileSortante>, Box<dyn Error>> {
+        let cle = cle_entree(uuid_message, idmg)?;
+        let cle = cle.as_str();
+        let repertoire_non_livrables = self.repertoire.join(REPERTOIRE_NON_LIVRABLES);
+        let path_fichier = path_entree(&repertoire_non_livrables, cle, EXTENSION_ENTREE)?;
+        let mut entree: EntreeFileSortante = match fs::read(&path_fichier) {
+            Ok(contenu) => serde_json::from_slice(contenu.as_slice())?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => Err(e)?
+        };
+
+        entree.tentatives = 0;
+        entree.confirmation = None;
+        entree.non_livrable = false;
+        entree.retry_after = None;
+        entree.prochain_essai = DateEpochSeconds::now();
+        self.ajouter(&entree)?;
+        supprimer_entree(&repertoire_non_livrables, cle)?;
+
+        Ok(Some(entree))
+    }
+
+    /// Copie des entrees de la file (filtrees par message et/ou idmg), avec l'indicateur de
+    /// tentative en cours.
+    pub fn lister(&self, uuid_message: Option<&str>, idmg: Option<&str>) -> Vec<(EntreeFileSortante, bool)> {
+        let entrees = self.entrees.lock().expect("lock entrees");
+        let en_cours = self.en_cours.lock().expect("lock en_cours");
+        entrees.iter()
+            .filter(|(_, e)| correspond(e, uuid_message, idmg))
+            .map(|(cle, e)| (e.clone(), en_cours.contains(cle)))
+            .collect()
+    }
+
+    /// Reserve et retourne les entrees dont la date de prochain essai est passee.
+    pub fn reserver_pretes(&self) -> Vec<EntreeFileSortante> {
+        let maintenant = Utc::now();
+        let entrees = self.entrees.lock().expect("lock entrees");
+        let mut en_cours = self.en_cours.lock().expect("lock en_cours");
+
+        let mut pretes = Vec::new();
+        for (cle, entree) in entrees.iter() {
+            if en_cours.contains(cle) { continue }
+            if entree.prochain_essai.get_datetime() <= &maintenant {
+                en_cours.insert(cle.clone());
+                pretes.push(entree.clone());
+            }
+        }
+
+        pretes
+    }
+
+    pub fn liberer(&self, cle: &str) {
+        if ! self.en_cours.lock().expect("lock en_cours").remove(cle) {
+            error!("FileSortante.liberer Entree {} n'etait pas reservee", cle);
+        }
+    }
+}
+
+fn correspond(entree: &EntreeFileSortante, uuid_message: Option<&str>, idmg: Option<&str>) -> bool {
+    uuid_message.map(|u| u == entree.uuid_message.as_str()).unwrap_or(true) &&
+        idmg.map(|i| i == entree.destination.idmg.as_str()).unwrap_or(true)
+}
+
+/// Cle d'une entree a partir des valeurs recues dans une commande. Refuse tout ce qui pourrait
+/// sortir du repertoire de la file (uuid et idmg sont alphanumeriques, avec tirets pour le uuid).
+pub fn cle_entree(uuid_message: &str, idmg: &str) -> Result<String, Box<dyn Error>> {
+    for valeur in [uuid_message, idmg] {
+        if valeur.is_empty() || ! valeur.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            Err(format!("file_sortante.cle_entree Valeur invalide : {:?}", valeur))?
+        }
+    }
+    Ok(format!("{}_{}", uuid_message, idmg))
+}
+
+/// Path du fichier d'une entree, la cle est validee avant d'etre utilisee comme nom de fichier.
+fn path_entree(repertoire: &Path, cle: &str, extension: &str) -> Result<PathBuf, Box<dyn Error>> {
+    if cle.is_empty() || ! cle.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        Err(format!("file_sortante.path_entree Cle invalide : {:?}", cle))?
+    }
+    Ok(repertoire.join(format!("{}.{}", cle, extension)))
+}
+
+fn ecrire_entree(repertoire: &Path, entree: &EntreeFileSortante) -> Result<(), Box<dyn Error>> {
+    let cle = cle_entree(entree.uuid_message.as_str(), entree.destination.idmg.as_str())?;
+    let path_fichier = path_entree(repertoire, cle.as_str(), EXTENSION_ENTREE)?;
+    let path_tmp = path_entree(repertoire, cle.as_str(), "tmp")?;
+    fs::write(&path_tmp, serde_json::to_vec(entree)?)?;
+    fs::rename(&path_tmp, &path_fichier)?;
+    Ok(())
+}
+
+fn supprimer_entree(repertoire: &Path, cle: &str) -> Result<(), Box<dyn Error>> {
+    let path_fichier = path_entree(repertoire, cle, EXTENSION_ENTREE)?;
+    if let Err(e) = fs::remove_file(&path_fichier) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            Err(e)?
+        }
+    }
+    Ok(())
+}
+
+fn lire_entrees(repertoire: &Path) -> Result<Vec<EntreeFileSortante>, Box<dyn Error>> {
+    let mut entrees = Vec::new();
+    for fichier in fs::read_dir(repertoire)? {
+        let path = fichier?.path();
+        match path.extension() {
+            Some(ext) if ext == EXTENSION_ENTREE => (),
+            _ => continue
+        }
+        match fs::read(&path) {
+            Ok(contenu) => match serde_json::from_slice(contenu.as_slice()) {
+                Ok(e) => entrees.push(e),
+                Err(e) => warn!("file_sortante.lire_entrees Entree invalide {:?} : {:?}", path, e)
+            },
+            Err(e) => warn!("file_sortante.lire_entrees Erreur lecture {:?} : {:?}", path, e)
+        }
+    }
+    Ok(entrees)
+}
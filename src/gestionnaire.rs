@@ -1,5 +1,6 @@
 use std::error::Error;
-use std::sync::Arc;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
 
 use log::{debug, error, info, warn};
 
@@ -12,20 +13,52 @@ use millegrilles_common_rust::rabbitmq_dao::{ConfigQueue, ConfigRoutingExchange,
 use millegrilles_common_rust::recepteur_messages::MessageValideAction;
 use millegrilles_common_rust::async_trait::async_trait;
 use millegrilles_common_rust::certificats::EnveloppePrivee;
+use millegrilles_common_rust::futures::future::join;
 use millegrilles_common_rust::reqwest;
 use millegrilles_common_rust::reqwest::Client;
+use millegrilles_common_rust::tokio;
+use millegrilles_common_rust::tokio::sync::{mpsc, Notify};
 use millegrilles_common_rust::tokio::time::{Duration, sleep};
-use crate::commandes::consommer_commande;
-
+use crate::commandes::{consommer_commande, executer_travaux_attachments, traiter_file_sortante, TravailAttachments};
+
+use crate::annulations::Annulations;
+use crate::cache_fiches::CacheFiches;
+use crate::clients_remote::ClientsRemote;
+use crate::concurrence::LimiteurConcurrence;
+use crate::config::lire_env;
+use crate::debit::LimiteurDebit;
+use crate::delais_remote::DelaisRemote;
 use crate::constantes::*;
+use crate::endpoints::EndpointsRemote;
 use crate::evenements::consommer_evenement;
+use crate::file_sortante::FileSortante;
+use crate::progres_uploads::ProgresUploads;
 use crate::requetes::consommer_requete;
+use crate::source_attachment::AttachmentSource;
+use crate::suspensions::IdmgsSuspendus;
+
+/// Commandes pousserAttachment en attente d'execution (Messagerie repousse la commande si la queue est pleine).
+const TAILLE_QUEUE_ATTACHMENTS: usize = 1000;
 
 #[derive(Debug)]
 pub struct GestionnairePostmaster {
     // tx_pompe_messages: Mutex<Option<Sender<MessagePompe>>>,
-    pub http_client_local: Option<Client>,
-    pub http_client_remote: Option<Client>,
+    pub source_attachments: Option<Arc<dyn AttachmentSource>>,
+    pub clients_remote: Arc<ClientsRemote>,
+    pub file_sortante: Arc<FileSortante>,
+    pub endpoints: Arc<EndpointsRemote>,
+    pub concurrence: Arc<LimiteurConcurrence>,
+    pub cache_fiches: Arc<CacheFiches>,
+    pub delais_remote: Arc<DelaisRemote>,
+    pub debit: Arc<LimiteurDebit>,
+    pub annulations: Arc<Annulations>,
+    pub progres_uploads: Arc<ProgresUploads>,
+    pub idmgs_suspendus: Arc<IdmgsSuspendus>,
+    /// Queue des uploads d'attachments, executes par le thread d'entretien hors de la Q de commandes.
+    pub tx_attachments: mpsc::Sender<TravailAttachments>,
+    rx_attachments: Arc<Mutex<Option<mpsc::Receiver<TravailAttachments>>>>,
+    /// Reveille le traitement de la file sortante avant l'intervalle d'entretien (e.g. reessayerMaintenant).
+    pub reveil_file_sortante: Arc<Notify>,
 }
 
 #[async_trait]
@@ -69,9 +102,26 @@ impl GestionnaireMessages for GestionnairePostmaster {
 
     async fn entretien<M>(&self, middleware: Arc<M>) where M: MiddlewareMessages + 'static {
         info!("gestionnaire Debut thread entretien");
-        loop {
-            sleep(Duration::new(30, 0)).await;
-        }
+        let rx_attachments = self.rx_attachments.lock().expect("lock rx_attachments").take();
+
+        let entretien_file_sortante = async {
+            loop {
+                tokio::select! {
+                    _ = sleep(Duration::new(30, 0)) => (),
+                    _ = self.reveil_file_sortante.notified() => debug!("gestionnaire Traitement immediat de la file sortante"),
+                }
+                traiter_file_sortante(middleware.as_ref(), self).await;
+                self.cache_fiches.emettre_statistiques();
+            }
+        };
+        let travaux_attachments = async {
+            match rx_attachments {
+                Some(rx) => executer_travaux_attachments(middleware.as_ref(), self, rx).await,
+                None => error!("gestionnaire Queue d'attachments deja consommee")
+            }
+        };
+        join(entretien_file_sortante, travaux_attachments).await;
+
         info!("gestionnaire Fin thread entretien");
     }
 
@@ -87,17 +137,47 @@ impl GestionnaireMessages for GestionnairePostmaster {
 impl Clone for GestionnairePostmaster {
     fn clone(&self) -> Self {
         GestionnairePostmaster {
-            http_client_local: self.http_client_local.clone(),
-            http_client_remote: self.http_client_remote.clone(),
+            source_attachments: self.source_attachments.clone(),
+            clients_remote: self.clients_remote.clone(),
+            file_sortante: self.file_sortante.clone(),
+            endpoints: self.endpoints.clone(),
+            concurrence: self.concurrence.clone(),
+            cache_fiches: self.cache_fiches.clone(),
+            delais_remote: self.delais_remote.clone(),
+            debit: self.debit.clone(),
+            annulations: self.annulations.clone(),
+            progres_uploads: self.progres_uploads.clone(),
+            idmgs_suspendus: self.idmgs_suspendus.clone(),
+            tx_attachments: self.tx_attachments.clone(),
+            rx_attachments: self.rx_attachments.clone(),
+            reveil_file_sortante: self.reveil_file_sortante.clone(),
         }
     }
 }
 
 impl GestionnairePostmaster {
     pub fn new() -> GestionnairePostmaster {
+        let repertoire_file_sortante: String = lire_env(ENV_FILE_SORTANTE)
+            .unwrap_or_else(|| DEFAULT_REPERTOIRE_FILE_SORTANTE.into());
+
+        let path_idmgs_suspendus = Path::new(repertoire_file_sortante.as_str()).join(FICHIER_IDMGS_SUSPENDUS);
+        let (tx_attachments, rx_attachments) = mpsc::channel(TAILLE_QUEUE_ATTACHMENTS);
+
         return GestionnairePostmaster {
-            http_client_local: None,
-            http_client_remote: None,
+            source_attachments: None,
+            clients_remote: Arc::new(ClientsRemote::charger_env()),
+            file_sortante: Arc::new(FileSortante::new(repertoire_file_sortante)),
+            endpoints: Arc::new(EndpointsRemote::new()),
+            concurrence: Arc::new(LimiteurConcurrence::charger_env()),
+            cache_fiches: Arc::new(CacheFiches::charger_env()),
+            delais_remote: Arc::new(DelaisRemote::new()),
+            debit: Arc::new(LimiteurDebit::charger_env()),
+            annulations: Arc::new(Annulations::new()),
+            progres_uploads: Arc::new(ProgresUploads::new()),
+            idmgs_suspendus: Arc::new(IdmgsSuspendus::new(path_idmgs_suspendus)),
+            tx_attachments,
+            rx_attachments: Arc::new(Mutex::new(Some(rx_attachments))),
+            reveil_file_sortante: Arc::new(Notify::new()),
         }
     }
 
@@ -114,11 +194,51 @@ pub fn preparer_queues() -> Vec<QueueType> {
     let commandes_publiques: Vec<&str> = vec![
         COMMANDE_POSTER,
         COMMANDE_POUSSER_ATTACHMENT,
+        COMMANDE_POUSSER_ATTACHMENT_DESTINATIONS,
     ];
     for cmd in commandes_publiques {
         rk_volatils.push(ConfigRoutingExchange {routing_key: format!("commande.{}.{}", DOMAINE_NOM, cmd), exchange: Securite::L1Public});
     }
 
+    // RK 2.prive
+    let commandes_privees: Vec<&str> = vec![
+        COMMANDE_ANNULER_TRANSMISSION,
+    ];
+    for cmd in commandes_privees {
+        rk_volatils.push(ConfigRoutingExchange {routing_key: format!("commande.{}.{}", DOMAINE_NOM, cmd), exchange: Securite::L2Prive});
+    }
+    let requetes_privees: Vec<&str> = vec![
+        REQUETE_ETAT_TRANSMISSION,
+        REQUETE_LISTER_TRANSMISSIONS_EN_COURS,
+    ];
+    for req in requetes_privees {
+        rk_volatils.push(ConfigRoutingExchange {routing_key: format!("requete.{}.{}", DOMAINE_NOM, req), exchange: Securite::L2Prive});
+    }
+
+    // RK 3.protege
+    let commandes_protegees: Vec<&str> = vec![
+        COMMANDE_ANNULER_TRANSMISSION,
+        COMMANDE_REJOUER_NON_LIVRABLE,
+        COMMANDE_REESSAYER_MAINTENANT,
+        COMMANDE_PURGER_FILE,
+        COMMANDE_SUSPENDRE_IDMG,
+        COMMANDE_REPRENDRE_IDMG,
+    ];
+    for cmd in commandes_protegees {
+        rk_volatils.push(ConfigRoutingExchange {routing_key: format!("commande.{}.{}", DOMAINE_NOM, cmd), exchange: Securite::L3Protege});
+    }
+    let requetes_protegees: Vec<&str> = vec![
+        REQUETE_NON_LIVRABLES,
+        REQUETE_ETAT_TRANSMISSION,
+        REQUETE_LISTER_TRANSMISSIONS_EN_COURS,
+    ];
+    for req in requetes_protegees {
+        rk_volatils.push(ConfigRoutingExchange {routing_key: format!("requete.{}.{}", DOMAINE_NOM, req), exchange: Securite::L3Protege});
+    }
+
+    // Evenements CoreTopologie
+    rk_volatils.push(ConfigRoutingExchange {routing_key: format!("evenement.{}.{}", DOMAINE_TOPOLOGIE, EVENEMENT_FICHE_PUBLIQUE), exchange: Securite::L3Protege});
+
     let mut queues = Vec::new();
 
     // Queue de messages volatils (requete, commande, evenements)
@@ -175,13 +295,3 @@ pub fn new_client_local(enveloppe_privee: &EnveloppePrivee) -> Result<Client, Bo
 
     Ok(client)
 }
-
-pub fn new_client_remote() -> Result<Client, Box<dyn Error>> {
-    let client = reqwest::Client::builder()
-        .https_only(true)
-        .use_rustls_tls()
-        .http2_adaptive_window(true)
-        .danger_accept_invalid_certs(true)  // Millegrille tierce
-        .build()?;
-    Ok(client)
-}
\ No newline at end of file
@@ -2,9 +2,22 @@ mod postmaster;
 mod gestionnaire;
 mod constantes;
 mod requetes;
+mod annulations;
+mod cache_fiches;
+mod clients_remote;
 mod commandes;
+mod concurrence;
+mod config;
+mod debit;
+mod delais_remote;
 mod evenements;
+mod endpoints;
+mod file_sortante;
 mod messages_struct;
+mod progres_uploads;
+mod source_attachment;
+mod suspensions;
+mod tee_attachment;
 mod transfert_fichier;
 
 use log::{info};
@@ -7,7 +7,8 @@ use millegrilles_common_rust::chrono::Utc;
 use millegrilles_common_rust::formatteur_messages::{DateEpochSeconds, Entete};
 use millegrilles_common_rust::serde::{Deserialize, Serialize};
 use millegrilles_common_rust::serde_json::{Map, Value};
-use crate::constantes::{CODE_UPLOAD_DEBUT, CODE_UPLOAD_ERREUR, CODE_UPLOAD_TERMINE};
+use crate::progres_uploads::ProgresUpload;
+use crate::constantes::{CODE_UPLOAD_ANNULE, CODE_UPLOAD_CORROMPU, CODE_UPLOAD_DEBUT, CODE_UPLOAD_ENCOURS, CODE_UPLOAD_ERREUR, CODE_UPLOAD_TERMINE};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct DocumentMessage {
@@ -58,6 +59,11 @@ pub struct FicheMillegrilleApplication {
     pub chiffrage: Option<Vec<Vec<String>>>,
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EvenementFicheMillegrille {
+    pub idmg: String,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct FicheApplication {
     pub application: String,
@@ -71,12 +77,106 @@ pub struct ConfirmationTransmission {
     pub idmg: String,
     pub destinataires: Vec<ConfirmationTransmissionDestinataire>,
     pub code: u16,
+    /// Delai (secondes) demande par la millegrille distante lors de la derniere tentative.
+    pub retry_after: Option<u32>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ConfirmationTransmissionDestinataire {
     pub destinataire: String,
     pub code: u32,
+    pub raison: Option<String>,
+}
+
+/// Reponse de la millegrille tierce a un POST /poster.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReponsePosterMessage {
+    pub ok: Option<bool>,
+    pub code: Option<u32>,
+    pub destinataires: Option<Vec<ReponsePosterDestinataire>>,
+}
+
+impl ReponsePosterMessage {
+    pub fn get_destinataire(&self, destinataire: &str) -> Option<&ReponsePosterDestinataire> {
+        match self.destinataires.as_ref() {
+            Some(d) => d.iter().find(|r| r.destinataire.as_str() == destinataire),
+            None => None
+        }
+    }
+}
+
+/// Resultat pour un destinataire (e.g. 200 ok, 403 refuse, 404 usager inconnu, 507 boite pleine).
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReponsePosterDestinataire {
+    pub destinataire: String,
+    pub code: u32,
+    pub raison: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TentativeTransmission {
+    pub date: DateEpochSeconds,
+    pub code: u16,
+    pub raison: Option<String>,
+}
+
+/// Evenement emis lorsqu'un message ne peut etre livre apres tous les retry (bounce).
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EvenementNonLivrable {
+    pub uuid_message: String,
+    pub idmg: String,
+    pub destinataires: Vec<String>,
+    pub code: u16,
+    pub raison: Option<String>,
+    pub tentatives: u32,
+    pub historique: Vec<TentativeTransmission>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CommandeRejouerNonLivrable {
+    pub uuid_message: String,
+    pub idmg: String,
+}
+
+/// Annule la transmission d'un message. Sans idmg, toutes les destinations sont annulees. Avec un
+/// fuuid, seul l'upload de cet attachment est annule (le message lui-meme est conserve).
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CommandeAnnulerTransmission {
+    pub uuid_message: String,
+    pub idmg: Option<String>,
+    pub fuuid: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EvenementTransmissionAnnulee {
+    pub uuid_message: String,
+    pub idmg: Option<String>,
+    pub fuuid: Option<String>,
+    /// Destinations retirees de la file sortante (retry annules).
+    pub idmgs_retires: Vec<String>,
+    pub uploads_interrompus: usize,
+}
+
+/// Force un essai immediat des entrees de la file (filtrees par message et/ou idmg).
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CommandeReessayerMaintenant {
+    pub uuid_message: Option<String>,
+    pub idmg: Option<String>,
+}
+
+/// Retire des entrees de la file sans les livrer. Au moins un filtre (message ou idmg) est requis.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CommandePurgerFile {
+    pub uuid_message: Option<String>,
+    pub idmg: Option<String>,
+    /// Supprime aussi les non livrables correspondants.
+    pub non_livrables: Option<bool>,
+}
+
+/// Commandes suspendreIdmg et reprendreIdmg.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CommandeSuspensionIdmg {
+    pub idmg: String,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -85,6 +185,60 @@ pub struct CommandePousserAttachments {
     pub idmg_destination: String,
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CommandePousserAttachmentDestinations {
+    pub uuid_message: String,
+    pub idmgs_destination: Vec<String>,
+}
+
+/// Demande du prochain attachment a Messagerie. Les fuuids exclus ont ete sautes par le postmaster.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CommandeProchainAttachment {
+    pub uuid_message: String,
+    pub idmg_destination: String,
+    pub fuuids_exclus: Vec<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RequeteEtatTransmission {
+    pub uuid_message: String,
+    pub idmg: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RequeteListerTransmissionsEnCours {
+    pub idmg: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EtatTransmission {
+    /// Tentative de transmission en cours.
+    EnCours,
+    /// En attente du prochain essai.
+    EnAttente,
+    /// Message traite, seule la confirmation vers Messagerie reste a emettre.
+    Confirmation,
+    NonLivrable,
+    /// Message livre (retire de la file), des attachments sont en cours d'upload.
+    Attachments,
+}
+
+/// Etat de la transmission d'un message vers une millegrille tierce.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EtatTransmissionDestination {
+    pub uuid_message: String,
+    pub idmg: String,
+    pub etat: EtatTransmission,
+    pub tentatives: u32,
+    pub dernier_code: Option<u16>,
+    /// Prochain essai, incluant le delai demande par la millegrille distante (Retry-After).
+    pub prochain_essai: Option<DateEpochSeconds>,
+    pub retry_after: Option<u32>,
+    pub date_creation: Option<DateEpochSeconds>,
+    pub uploads: Vec<ProgresUpload>,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct RequeteTopologieFicheApplication {
     pub idmgs: Vec<String>,
@@ -106,6 +260,12 @@ pub struct EvenementUploadAttachment {
     pub http_status: Option<u16>,
     pub retry_after: Option<u32>,
     pub complete: bool,
+    /// Progres de l'upload : bytes recus par la millegrille distante, taille totale et debit (bytes/sec).
+    pub position: Option<usize>,
+    pub taille: Option<usize>,
+    pub debit: Option<u64>,
+    /// URLs de la millegrille distante essayes avant l'echec de l'upload.
+    pub urls_essayees: Option<Vec<String>>,
 }
 
 impl EvenementUploadAttachment {
@@ -118,6 +278,10 @@ impl EvenementUploadAttachment {
             http_status: None,
             retry_after: None,
             complete: false,
+            position: None,
+            taille: None,
+            debit: None,
+            urls_essayees: None,
         }
     }
 
@@ -130,6 +294,10 @@ impl EvenementUploadAttachment {
             http_status: Some(http_status),
             retry_after: None,
             complete: true,
+            position: None,
+            taille: None,
+            debit: None,
+            urls_essayees: None,
         }
     }
 
@@ -142,6 +310,60 @@ impl EvenementUploadAttachment {
             http_status: Some(http_status),
             retry_after: None,
             complete: false,
+            position: None,
+            taille: None,
+            debit: None,
+            urls_essayees: None,
+        }
+    }
+
+    pub fn progres(uuid_message: String, idmg: String, fuuid: String, position: usize, taille: Option<usize>, debit: u64) -> Self {
+        EvenementUploadAttachment {
+            uuid_message,
+            idmg,
+            fuuid,
+            code: CODE_UPLOAD_ENCOURS,
+            http_status: None,
+            retry_after: None,
+            complete: false,
+            position: Some(position),
+            taille,
+            debit: Some(debit),
+            urls_essayees: None,
+        }
+    }
+
+    /// Le contenu lu localement ne correspond pas au hachage du fuuid, le fichier n'est pas livre.
+    pub fn corrompu(uuid_message: String, idmg: String, fuuid: String) -> Self {
+        EvenementUploadAttachment {
+            uuid_message,
+            idmg,
+            fuuid,
+            code: CODE_UPLOAD_CORROMPU,
+            http_status: None,
+            retry_after: None,
+            complete: false,
+            position: None,
+            taille: None,
+            debit: None,
+            urls_essayees: None,
+        }
+    }
+
+    /// L'upload a ete annule (commande annulerTransmission).
+    pub fn annule(uuid_message: String, idmg: String, fuuid: String) -> Self {
+        EvenementUploadAttachment {
+            uuid_message,
+            idmg,
+            fuuid,
+            code: CODE_UPLOAD_ANNULE,
+            http_status: None,
+            retry_after: None,
+            complete: false,
+            position: None,
+            taille: None,
+            debit: None,
+            urls_essayees: None,
         }
     }
 }
@@ -151,4 +373,18 @@ pub struct ResponsePutFichierPartiel {
     pub ok: bool,
     pub code: Option<u32>,
     pub status: Option<usize>,
-}
\ No newline at end of file
+}
+
+/// Etat d'un upload partiel sur la millegrille distante (GET /poster/{fuuid}).
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReponseEtatUploadPartiel {
+    pub ok: Option<bool>,
+    pub code: Option<u32>,
+    pub parts: Option<Vec<PartUploadRecue>>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PartUploadRecue {
+    pub position: usize,
+    pub taille: usize,
+}
@@ -17,7 +17,10 @@ use millegrilles_common_rust::tokio_stream::StreamExt;
 use millegrilles_common_rust::rabbitmq_dao::{Callback, EventMq, QueueType};
 use millegrilles_common_rust::recepteur_messages::TypeMessage;
 
+use crate::config::lire_env;
+use crate::constantes::ENV_REPERTOIRE_ATTACHMENTS;
 use crate::gestionnaire::*;
+use crate::source_attachment::{AttachmentSource, SourceFichiersHttp, SourceRepertoireLocal};
 
 static mut POSTMASTER: TypeGestionnaire = TypeGestionnaire::None;
 
@@ -51,6 +54,15 @@ fn charger_gestionnaire(gestionnaire: GestionnairePostmaster) -> &'static TypeGe
 async fn build() -> (FuturesUnordered<JoinHandle<()>>, Arc<MiddlewareMessage>) {
 
     let mut gestionnaire_mut = GestionnairePostmaster::new();
+    if let Err(e) = gestionnaire_mut.file_sortante.charger() {
+        // Les messages poster sont refuses (confirmation en erreur) : sans persistance, ils seraient
+        // perdus au redemarrage
+        error!("Erreur chargement file sortante, les messages a transmettre seront refuses : {:?}", e);
+        gestionnaire_mut.file_sortante.marquer_indisponible();
+    }
+    if let Err(e) = gestionnaire_mut.idmgs_suspendus.charger() {
+        error!("Erreur chargement des idmgs suspendus : {:?}", e);
+    }
 
     // Recuperer configuration des Q de tous les domaines
     let queues = {
@@ -82,19 +94,8 @@ async fn build() -> (FuturesUnordered<JoinHandle<()>>, Arc<MiddlewareMessage>) {
     let middleware = middleware_hooks.middleware;
 
     // Wiring final du gestionnaire
-    let gestionnaire_static = match new_client_local(middleware.get_enveloppe_privee().as_ref()) {
-        Ok(client_local) => {
-            gestionnaire_mut.http_client_local = Some(client_local);
-            match new_client_remote() {
-                Ok(client_remote) => {
-                    gestionnaire_mut.http_client_remote = Some(client_remote);
-                    charger_gestionnaire(gestionnaire_mut)
-                },
-                Err(e) => panic!("Erreur creation client reqwest pour fichiers remote : {:?}", e)
-            }
-        },
-        Err(e) => panic!("Erreur creation client reqwest pour fichiers locaux : {:?}", e)
-    };
+    gestionnaire_mut.source_attachments = preparer_source_attachments(middleware.as_ref());
+    let gestionnaire_static = charger_gestionnaire(gestionnaire_mut);
 
     // Preparer les green threads de tous les domaines/processus
     let mut futures = FuturesUnordered::new();
@@ -224,3 +225,30 @@ async fn entretien<M>(middleware: Arc<M>, mut rx: Receiver<EventMq>, gestionnair
 
     info!("Fin thread entretien");
 }
+
+/// Source des attachments : repertoire local si configure, sinon le serveur de fichiers (https).
+/// Sans source, les uploads d'attachments echouent mais les messages sont transmis.
+fn preparer_source_attachments<M>(middleware: &M) -> Option<Arc<dyn AttachmentSource>>
+    where M: IsConfigNoeud + IsConfigurationPki
+{
+    if let Some(repertoire) = lire_env::<String>(ENV_REPERTOIRE_ATTACHMENTS) {
+        info!("preparer_source_attachments Attachments lus dans le repertoire {}", repertoire);
+        return Some(Arc::new(SourceRepertoireLocal::new(repertoire)))
+    }
+
+    let url_fichiers = match &middleware.get_configuration_noeud().fichiers_url {
+        Some(u) => u.clone(),
+        None => {
+            error!("preparer_source_attachments URL fichiers n'est pas disponible, aucune source d'attachments");
+            return None
+        }
+    };
+
+    match new_client_local(middleware.get_enveloppe_privee().as_ref()) {
+        Ok(client_local) => Some(Arc::new(SourceFichiersHttp::new(client_local, url_fichiers))),
+        Err(e) => {
+            error!("preparer_source_attachments Erreur creation client reqwest pour fichiers locaux : {:?}", e);
+            None
+        }
+    }
+}
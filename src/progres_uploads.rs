@@ -0,0 +1,66 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use millegrilles_common_rust::formatteur_messages::DateEpochSeconds;
+use millegrilles_common_rust::serde::{Deserialize, Serialize};
+
+/// Progres d'un upload d'attachment en cours.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ProgresUpload {
+    pub uuid_message: String,
+    pub idmg: String,
+    pub fuuid: String,
+    /// Bytes confirmes par la millegrille distante.
+    pub position: usize,
+    pub taille: Option<usize>,
+    /// Debit moyen (bytes/sec) depuis le debut de l'upload.
+    pub debit: u64,
+    pub date_debut: DateEpochSeconds,
+}
+
+/// Uploads en cours, pour les requetes d'etat (sans avoir a ecouter les evenements de progres).
+#[derive(Debug)]
+pub struct ProgresUploads {
+    uploads: Mutex<HashMap<u64, ProgresUpload>>,
+    prochain_id: Mutex<u64>,
+}
+
+impl ProgresUploads {
+    pub fn new() -> Self {
+        ProgresUploads {
+            uploads: Mutex::new(HashMap::new()),
+            prochain_id: Mutex::new(0),
+        }
+    }
+
+    /// Enregistre un nouvel upload, retourne son identifiant pour les mises a jour.
+    pub fn ajouter(&self, progres: ProgresUpload) -> u64 {
+        let id = {
+            let mut prochain_id = self.prochain_id.lock().expect("lock prochain_id");
+            *prochain_id += 1;
+            *prochain_id
+        };
+        self.uploads.lock().expect("lock uploads").insert(id, progres);
+        id
+    }
+
+    pub fn maj(&self, id: u64, position: usize, debit: u64) {
+        if let Some(p) = self.uploads.lock().expect("lock uploads").get_mut(&id) {
+            p.position = position;
+            p.debit = debit;
+        }
+    }
+
+    pub fn retirer(&self, id: u64) {
+        self.uploads.lock().expect("lock uploads").remove(&id);
+    }
+
+    /// Uploads en cours, filtres par message et/ou idmg.
+    pub fn lister(&self, uuid_message: Option<&str>, idmg: Option<&str>) -> Vec<ProgresUpload> {
+        self.uploads.lock().expect("lock uploads").values()
+            .filter(|p| uuid_message.map(|u| u == p.uuid_message.as_str()).unwrap_or(true))
+            .filter(|p| idmg.map(|i| i == p.idmg.as_str()).unwrap_or(true))
+            .cloned()
+            .collect()
+    }
+}
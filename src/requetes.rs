@@ -1,13 +1,21 @@
+use std::collections::HashMap;
 use std::error::Error;
 use log::{debug, error, info, warn};
 
 use millegrilles_common_rust::certificats::{ValidateurX509, VerificateurPermissions};
 use millegrilles_common_rust::constantes::{DELEGATION_GLOBALE_PROPRIETAIRE, RolesCertificats, Securite};
-use millegrilles_common_rust::formatteur_messages::MessageMilleGrille;
+use millegrilles_common_rust::formatteur_messages::{DateEpochSeconds, MessageMilleGrille};
 use millegrilles_common_rust::generateur_messages::GenerateurMessages;
 use millegrilles_common_rust::recepteur_messages::MessageValideAction;
+use millegrilles_common_rust::serde_json::json;
 use millegrilles_common_rust::verificateur::VerificateurMessage;
+
+use crate::commandes::{verifier_autorisation_admin, verifier_autorisation_message};
+use crate::constantes::*;
+use crate::file_sortante::EntreeFileSortante;
 use crate::gestionnaire::GestionnairePostmaster;
+use crate::messages_struct::*;
+use crate::progres_uploads::ProgresUpload;
 
 pub async fn consommer_requete<M>(middleware: &M, message: MessageValideAction, gestionnaire: &GestionnairePostmaster)
     -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
@@ -32,7 +40,9 @@ pub async fn consommer_requete<M>(middleware: &M, message: MessageValideAction,
     match message.domaine.as_str() {
         DOMAINE_NOM => {
             match message.action.as_str() {
-                // REQUETE_GET_MESSAGES => requete_get_messages(middleware, message, gestionnaire).await,
+                REQUETE_NON_LIVRABLES => requete_non_livrables(middleware, message, gestionnaire).await,
+                REQUETE_ETAT_TRANSMISSION => requete_etat_transmission(middleware, message, gestionnaire).await,
+                REQUETE_LISTER_TRANSMISSIONS_EN_COURS => requete_lister_transmissions_en_cours(middleware, message, gestionnaire).await,
                 _ => {
                     error!("Message requete/action inconnue : '{}'. Message dropped.", message.action);
                     Ok(None)
@@ -45,3 +55,143 @@ pub async fn consommer_requete<M>(middleware: &M, message: MessageValideAction,
         },
     }
 }
+
+async fn requete_non_livrables<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnairePostmaster)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages
+{
+    if ! verifier_autorisation_admin(&m) {
+        Err(format!("requetes.requete_non_livrables Autorisation invalide pour message {:?}", m.correlation_id))?
+    }
+
+    let non_livrables: Vec<_> = gestionnaire.file_sortante.lister_non_livrables()?.into_iter()
+        .map(|e| json!({
+            "uuid_message": e.uuid_message,
+            "idmg": e.destination.idmg,
+            "destinataires": e.destination.destinataires,
+            "date_creation": e.date_creation,
+            "tentatives": e.tentatives,
+            "dernier_code": e.dernier_code,
+            "historique": e.historique,
+        }))
+        .collect();
+
+    let reponse = json!({"ok": true, "non_livrables": non_livrables});
+    Ok(Some(middleware.formatter_reponse(&reponse, None)?))
+}
+
+/// Etat de chaque destination d'un message (file sortante, non livrables et uploads en cours).
+/// Reserve a un administrateur, a Messagerie ou au proprietaire du message.
+async fn requete_etat_transmission<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnairePostmaster)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages + ValidateurX509
+{
+    let requete: RequeteEtatTransmission = m.message.parsed.map_contenu(None)?;
+    debug!("requete_etat_transmission Requete : {:?}", requete);
+    let uuid_message = requete.uuid_message.as_str();
+
+    if ! verifier_autorisation_message(middleware, &m, gestionnaire, uuid_message).await {
+        Err(format!("requetes.requete_etat_transmission Autorisation invalide pour message {:?}", m.correlation_id))?
+    }
+    let idmg = requete.idmg.as_ref().map(|i| i.as_str());
+
+    let mut destinations = etats_destinations(gestionnaire, Some(uuid_message), idmg);
+
+    let non_livrables = gestionnaire.file_sortante.lister_non_livrables()?.into_iter()
+        .filter(|e| e.uuid_message.as_str() == uuid_message)
+        .filter(|e| idmg.map(|i| i == e.destination.idmg.as_str()).unwrap_or(true));
+    for entree in non_livrables {
+        destinations.push(etat_entree(gestionnaire, &entree, EtatTransmission::NonLivrable, Vec::new()));
+    }
+
+    let reponse = json!({"ok": true, "uuid_message": uuid_message, "destinations": destinations});
+    Ok(Some(middleware.formatter_reponse(&reponse, None)?))
+}
+
+/// Transmissions dans la file sortante et uploads d'attachments en cours, optionnellement pour un idmg.
+/// Couvre les messages de tous les usagers, reserve aux administrateurs.
+async fn requete_lister_transmissions_en_cours<M>(middleware: &M, m: MessageValideAction, gestionnaire: &GestionnairePostmaster)
+    -> Result<Option<MessageMilleGrille>, Box<dyn Error>>
+    where M: GenerateurMessages
+{
+    if ! verifier_autorisation_admin(&m) {
+        Err(format!("requetes.requete_lister_transmissions_en_cours Autorisation invalide pour message {:?}", m.correlation_id))?
+    }
+
+    let requete: RequeteListerTransmissionsEnCours = m.message.parsed.map_contenu(None)?;
+    debug!("requete_lister_transmissions_en_cours Requete : {:?}", requete);
+
+    let transmissions = etats_destinations(gestionnaire, None, requete.idmg.as_ref().map(|i| i.as_str()));
+
+    let reponse = json!({"ok": true, "transmissions": transmissions});
+    Ok(Some(middleware.formatter_reponse(&reponse, None)?))
+}
+
+fn etats_destinations(gestionnaire: &GestionnairePostmaster, uuid_message: Option<&str>, idmg: Option<&str>)
+    -> Vec<EtatTransmissionDestination>
+{
+    let mut uploads: HashMap<(String, String), Vec<ProgresUpload>> = HashMap::new();
+    for progres in gestionnaire.progres_uploads.lister(uuid_message, idmg) {
+        uploads.entry((progres.uuid_message.clone(), progres.idmg.clone())).or_default().push(progres);
+    }
+
+    let mut etats = Vec::new();
+    for (entree, en_cours) in gestionnaire.file_sortante.lister(uuid_message, idmg) {
+        let etat = match en_cours {
+            true => EtatTransmission::EnCours,
+            false => match entree.confirmation {
+                Some(_) => EtatTransmission::Confirmation,
+                None => EtatTransmission::EnAttente
+            }
+        };
+        let cle = (entree.uuid_message.clone(), entree.destination.idmg.clone());
+        let uploads_destination = uploads.remove(&cle).unwrap_or_default();
+        etats.push(etat_entree(gestionnaire, &entree, etat, uploads_destination));
+    }
+
+    // Messages deja livres dont les attachments sont en cours d'upload
+    for ((uuid_message, idmg), uploads_destination) in uploads {
+        etats.push(EtatTransmissionDestination {
+            uuid_message,
+            idmg,
+            etat: EtatTransmission::Attachments,
+            tentatives: 0,
+            dernier_code: None,
+            prochain_essai: None,
+            retry_after: None,
+            date_creation: None,
+            uploads: uploads_destination,
+        });
+    }
+
+    etats
+}
+
+fn etat_entree(gestionnaire: &GestionnairePostmaster, entree: &EntreeFileSortante, etat: EtatTransmission, uploads: Vec<ProgresUpload>)
+    -> EtatTransmissionDestination
+{
+    let idmg = entree.destination.idmg.as_str();
+    let prochain_essai = match etat {
+        EtatTransmission::NonLivrable => None,
+        _ => {
+            // La millegrille distante peut avoir demande de ralentir depuis la derniere tentative
+            let mut prochain_essai = entree.prochain_essai.get_datetime().clone();
+            if let Some(date) = gestionnaire.delais_remote.get(idmg) {
+                prochain_essai = prochain_essai.max(date);
+            }
+            Some(DateEpochSeconds::from(prochain_essai))
+        }
+    };
+
+    EtatTransmissionDestination {
+        uuid_message: entree.uuid_message.clone(),
+        idmg: idmg.into(),
+        etat,
+        tentatives: entree.tentatives,
+        dernier_code: entree.dernier_code,
+        prochain_essai,
+        retry_after: entree.retry_after,
+        date_creation: Some(entree.date_creation.clone()),
+        uploads,
+    }
+}
@@ -0,0 +1,141 @@
+use std::error::Error;
+use std::fmt::Debug;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::pin::Pin;
+
+use log::debug;
+use millegrilles_common_rust::async_trait::async_trait;
+use millegrilles_common_rust::futures::stream::TryStreamExt;
+use millegrilles_common_rust::reqwest::{Client, Url};
+use millegrilles_common_rust::tokio::fs::File;
+use millegrilles_common_rust::tokio::io::AsyncRead;
+use tokio_util::io::StreamReader;
+
+/// Contenu d'un attachment ouvert aupres d'une source.
+pub struct FluxAttachment {
+    /// Taille du fichier, None si la source ne la connait pas.
+    pub taille: Option<usize>,
+    pub reader: Pin<Box<dyn AsyncRead + Send>>,
+}
+
+/// Source des attachments (fuuid) a transmettre vers les millegrilles tierces.
+#[async_trait]
+pub trait AttachmentSource: Debug + Send + Sync {
+    async fn ouvrir(&self, fuuid: &str) -> Result<FluxAttachment, Box<dyn Error>>;
+}
+
+/// Attachments lus via https aupres du serveur de fichiers local ({url_fichiers}/fichiers/{fuuid}).
+#[derive(Debug)]
+pub struct SourceFichiersHttp {
+    client: Client,
+    url_fichiers: Url,
+}
+
+impl SourceFichiersHttp {
+    pub fn new(client: Client, url_fichiers: Url) -> Self {
+        SourceFichiersHttp { client, url_fichiers }
+    }
+}
+
+#[async_trait]
+impl AttachmentSource for SourceFichiersHttp {
+    async fn ouvrir(&self, fuuid: &str) -> Result<FluxAttachment, Box<dyn Error>> {
+        let mut url_get_fichier = self.url_fichiers.clone();
+        url_get_fichier.set_path(format!("/fichiers/{}", fuuid).as_str());
+
+        let reponse = self.client.get(url_get_fichier).send().await?;
+        debug!("SourceFichiersHttp.ouvrir Reponse : {:?}", reponse);
+        if !reponse.status().is_success() {
+            Err(format!("source_attachment.SourceFichiersHttp Erreur ouverture fichier status {} : {}", reponse.status().as_u16(), reponse.url().as_str()))?
+        }
+
+        let taille = reponse.content_length().map(|t| t as usize);
+        let stream = reponse.bytes_stream().map_err(|e| std::io::Error::new(ErrorKind::Other, e));
+
+        Ok(FluxAttachment { taille, reader: Box::pin(StreamReader::new(stream)) })
+    }
+}
+
+/// Attachments lus directement dans un repertoire local ({repertoire}/{fuuid}), pour un postmaster
+/// qui roule sur le meme serveur que la consignation.
+#[derive(Debug)]
+pub struct SourceRepertoireLocal {
+    repertoire: PathBuf,
+}
+
+impl SourceRepertoireLocal {
+    pub fn new<P: Into<PathBuf>>(repertoire: P) -> Self {
+        SourceRepertoireLocal { repertoire: repertoire.into() }
+    }
+}
+
+#[async_trait]
+impl AttachmentSource for SourceRepertoireLocal {
+    async fn ouvrir(&self, fuuid: &str) -> Result<FluxAttachment, Box<dyn Error>> {
+        // Le fuuid est un multibase, refuser tout ce qui pourrait sortir du repertoire
+        if fuuid.is_empty() || ! fuuid.chars().all(|c| c.is_ascii_alphanumeric()) {
+            Err(format!("source_attachment.SourceRepertoireLocal Fuuid invalide : {}", fuuid))?
+        }
+
+        let path_fichier = self.repertoire.join(fuuid);
+        let fichier = File::open(&path_fichier).await?;
+        let taille = fichier.metadata().await?.len() as usize;
+        debug!("SourceRepertoireLocal.ouvrir {:?} ({} bytes)", path_fichier, taille);
+
+        Ok(FluxAttachment { taille: Some(taille), reader: Box::pin(fichier) })
+    }
+}
+
+#[cfg(test)]
+mod test_source_repertoire_local {
+    use super::*;
+    use std::fs;
+    use millegrilles_common_rust::tokio;
+    use millegrilles_common_rust::tokio::io::AsyncReadExt;
+    use crate::test_setup::setup;
+
+    fn preparer_repertoire(nom: &str) -> PathBuf {
+        let repertoire = std::env::temp_dir().join(format!("postmaster_{}_{}", nom, std::process::id()));
+        let _ = fs::remove_dir_all(&repertoire);
+        fs::create_dir_all(&repertoire).expect("create_dir_all");
+        repertoire
+    }
+
+    #[tokio::test]
+    async fn test_fuuid_invalide() {
+        setup("test_fuuid_invalide");
+        let repertoire = preparer_repertoire("test_fuuid_invalide");
+        let attachments = repertoire.join("attachments");
+        fs::create_dir_all(&attachments).expect("create_dir_all");
+        // Fichier hors du repertoire des attachments, ne doit pas etre accessible
+        fs::write(repertoire.join("secret"), b"secret").expect("write");
+
+        let source = SourceRepertoireLocal::new(&attachments);
+        for fuuid in ["", "..", "../secret", "/etc/passwd", "sous/fichier", "..\\secret", "zABC.tmp", "zABC "] {
+            assert!(source.ouvrir(fuuid).await.is_err(), "fuuid accepte : {:?}", fuuid);
+        }
+
+        let _ = fs::remove_dir_all(&repertoire);
+    }
+
+    #[tokio::test]
+    async fn test_ouvrir_attachment() {
+        setup("test_ouvrir_attachment");
+        let repertoire = preparer_repertoire("test_ouvrir_attachment");
+        let contenu = b"contenu de l'attachment chiffre";
+        fs::write(repertoire.join("zSEfXUDoL1JgyZ8pyuGVB7Vx"), contenu).expect("write");
+
+        let source = SourceRepertoireLocal::new(&repertoire);
+        let mut flux = source.ouvrir("zSEfXUDoL1JgyZ8pyuGVB7Vx").await.expect("ouvrir");
+        assert_eq!(Some(contenu.len()), flux.taille);
+        let mut lu = Vec::new();
+        flux.reader.read_to_end(&mut lu).await.expect("read_to_end");
+        assert_eq!(&contenu[..], lu.as_slice());
+
+        // Fuuid valide mais absent du repertoire
+        assert!(source.ouvrir("zFichierAbsent").await.is_err());
+
+        let _ = fs::remove_dir_all(&repertoire);
+    }
+}
@@ -0,0 +1,75 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use log::{debug, info};
+use millegrilles_common_rust::serde_json;
+
+/// Millegrilles tierces vers lesquelles les transmissions sont suspendues par un administrateur
+/// (commandes suspendreIdmg/reprendreIdmg). Conserve sur disque pour survivre a un redemarrage.
+#[derive(Debug)]
+pub struct IdmgsSuspendus {
+    path: PathBuf,
+    idmgs: Mutex<HashSet<String>>,
+}
+
+impl IdmgsSuspendus {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        IdmgsSuspendus { path: path.into(), idmgs: Mutex::new(HashSet::new()) }
+    }
+
+    pub fn charger(&self) -> Result<usize, Box<dyn Error>> {
+        let idmgs_fichier: Vec<String> = match fs::read(&self.path) {
+            Ok(contenu) => serde_json::from_slice(contenu.as_slice())?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => Err(e)?
+        };
+
+        let mut idmgs = self.idmgs.lock().expect("lock idmgs");
+        idmgs.extend(idmgs_fichier);
+        if idmgs.len() > 0 {
+            info!("IdmgsSuspendus.charger Transmissions suspendues vers {:?}", idmgs);
+        }
+        Ok(idmgs.len())
+    }
+
+    /// Retourne false si l'idmg etait deja suspendu.
+    pub fn suspendre(&self, idmg: &str) -> Result<bool, Box<dyn Error>> {
+        let mut idmgs = self.idmgs.lock().expect("lock idmgs");
+        if ! idmgs.insert(idmg.into()) {
+            return Ok(false)
+        }
+        if let Err(e) = self.sauvegarder(&idmgs) {
+            idmgs.remove(idmg);
+            Err(e)?
+        }
+        Ok(true)
+    }
+
+    /// Retourne false si l'idmg n'etait pas suspendu.
+    pub fn reprendre(&self, idmg: &str) -> Result<bool, Box<dyn Error>> {
+        let mut idmgs = self.idmgs.lock().expect("lock idmgs");
+        if ! idmgs.remove(idmg) {
+            return Ok(false)
+        }
+        if let Err(e) = self.sauvegarder(&idmgs) {
+            idmgs.insert(idmg.into());
+            Err(e)?
+        }
+        Ok(true)
+    }
+
+    pub fn est_suspendu(&self, idmg: &str) -> bool {
+        self.idmgs.lock().expect("lock idmgs").contains(idmg)
+    }
+
+    fn sauvegarder(&self, idmgs: &HashSet<String>) -> Result<(), Box<dyn Error>> {
+        let path_tmp = self.path.with_extension("tmp");
+        fs::write(&path_tmp, serde_json::to_vec(idmgs)?)?;
+        fs::rename(&path_tmp, &self.path)?;
+        debug!("IdmgsSuspendus.sauvegarder {:?} : {:?}", self.path, idmgs);
+        Ok(())
+    }
+}
@@ -0,0 +1,243 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use log::{debug, info, warn};
+use millegrilles_common_rust::futures::stream;
+use millegrilles_common_rust::tokio::fs::{create_dir_all, File};
+use millegrilles_common_rust::tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use millegrilles_common_rust::tokio::sync::{mpsc, watch};
+use tokio_util::io::StreamReader;
+
+use crate::config::lire_env;
+use crate::constantes::*;
+use crate::source_attachment::FluxAttachment;
+
+const TAILLE_CHUNK: usize = 65536;
+/// Nombre de chunks en memoire par destination avant de deborder sur disque.
+const CHUNKS_MEMOIRE: usize = 64;
+
+/// Compteur pour les noms de fichiers de debordement (un meme fuuid peut etre distribue par
+/// plusieurs commandes en parallele).
+static COMPTEUR_DEBORDEMENT: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Copy, Debug)]
+struct EtatDebordement {
+    ecrits: u64,
+    termine: bool,
+    erreur: bool,
+}
+
+/// Lit le fichier local une seule fois et le distribue a plusieurs destinations. Une destination
+/// lente deborde dans un fichier temporaire plutot que de bloquer les autres.
+pub struct DistributeurTee {
+    sorties: Vec<SortieTee>,
+}
+
+struct SortieTee {
+    nom: String,
+    path_debordement: PathBuf,
+    memoire: Option<mpsc::Sender<Vec<u8>>>,
+    debordement: Option<File>,
+    etat: watch::Sender<EtatDebordement>,
+    ecrits: u64,
+    fermee: bool,
+}
+
+struct LecteurTee {
+    memoire: Option<mpsc::Receiver<Vec<u8>>>,
+    etat: watch::Receiver<EtatDebordement>,
+    path_debordement: PathBuf,
+    fichier: Option<File>,
+    lus: u64,
+}
+
+/// Prepare un flux par destination (noms uniques, e.g. idmg) pour le fichier fuuid.
+pub fn preparer_tee(fuuid: &str, destinations: &Vec<String>, taille: Option<usize>) -> (DistributeurTee, Vec<FluxAttachment>) {
+    let mut sorties = Vec::new();
+    let mut flux = Vec::new();
+    let repertoire_debordement = lire_env::<PathBuf>(ENV_REPERTOIRE_DEBORDEMENT)
+        .unwrap_or_else(|| DEFAULT_REPERTOIRE_DEBORDEMENT.into());
+
+    for nom in destinations.iter() {
+        let compteur = COMPTEUR_DEBORDEMENT.fetch_add(1, Ordering::Relaxed);
+        let path_debordement = repertoire_debordement
+            .join(format!("postmaster_{}_{}_{}.tmp", fuuid, std::process::id(), compteur));
+        let (tx, rx) = mpsc::channel(CHUNKS_MEMOIRE);
+        let (etat_tx, etat_rx) = watch::channel(EtatDebordement { ecrits: 0, termine: false, erreur: false });
+
+        sorties.push(SortieTee {
+            nom: nom.clone(),
+            path_debordement: path_debordement.clone(),
+            memoire: Some(tx),
+            debordement: None,
+            etat: etat_tx,
+            ecrits: 0,
+            fermee: false,
+        });
+
+        let lecteur = LecteurTee { memoire: Some(rx), etat: etat_rx, path_debordement, fichier: None, lus: 0 };
+        let stream = stream::unfold(lecteur, |mut lecteur| async move {
+            match lecteur.prochain().await {
+                Some(resultat) => Some((resultat.map(std::io::Cursor::new), lecteur)),
+                None => None
+            }
+        });
+        let reader: Pin<Box<dyn AsyncRead + Send>> = Box::pin(StreamReader::new(stream));
+        flux.push(FluxAttachment { taille, reader });
+    }
+
+    (DistributeurTee { sorties }, flux)
+}
+
+impl DistributeurTee {
+    /// Lit le fichier local jusqu'a la fin et le pousse vers toutes les destinations.
+    pub async fn distribuer(mut self, mut reader: Pin<Box<dyn AsyncRead + Send>>) {
+        let mut buf = vec![0u8; TAILLE_CHUNK];
+        let mut erreur = false;
+        loop {
+            let len_read = match reader.read(&mut buf).await {
+                Ok(l) => l,
+                Err(e) => {
+                    warn!("DistributeurTee.distribuer Erreur lecture fichier local : {:?}", e);
+                    erreur = true;
+                    break
+                }
+            };
+            if len_read == 0 { break }
+
+            for sortie in self.sorties.iter_mut() {
+                sortie.envoyer(&buf[..len_read]).await;
+            }
+            if self.sorties.iter().all(|s| s.fermee) {
+                debug!("DistributeurTee.distribuer Toutes les destinations sont fermees");
+                break
+            }
+        }
+
+        for sortie in self.sorties.iter_mut() {
+            sortie.terminer(erreur);
+        }
+    }
+}
+
+impl SortieTee {
+    async fn envoyer(&mut self, data: &[u8]) {
+        if self.fermee { return }
+
+        if let Some(tx) = self.memoire.as_ref() {
+            match tx.try_send(data.to_vec()) {
+                Ok(()) => return,
+                Err(mpsc::error::TrySendError::Closed(_)) => {
+                    self.fermer();
+                    return
+                },
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    // Destination lente, le reste du fichier passe par le disque. Le lecteur vide
+                    // la memoire (fermee) avant de lire le fichier de debordement.
+                    info!("SortieTee.envoyer Destination {} lente, debordement vers {:?}", self.nom, self.path_debordement);
+                    self.memoire = None;
+                    if let Some(repertoire) = self.path_debordement.parent() {
+                        if let Err(e) = create_dir_all(repertoire).await {
+                            warn!("SortieTee.envoyer Erreur creation repertoire de debordement {:?} : {:?}", repertoire, e);
+                        }
+                    }
+                    match File::create(&self.path_debordement).await {
+                        Ok(f) => self.debordement = Some(f),
+                        Err(e) => {
+                            warn!("SortieTee.envoyer Erreur creation fichier de debordement {:?} : {:?}", self.path_debordement, e);
+                            self.terminer(true);
+                            self.fermer();
+                            return
+                        }
+                    }
+                }
+            }
+        }
+
+        // Flush pour que les bytes annonces au lecteur soient sur disque
+        let resultat = match self.debordement.as_mut() {
+            Some(f) => match f.write_all(data).await {
+                Ok(()) => f.flush().await,
+                Err(e) => Err(e)
+            },
+            None => return
+        };
+        match resultat {
+            Ok(()) => {
+                self.ecrits += data.len() as u64;
+                if self.etat.send(EtatDebordement { ecrits: self.ecrits, termine: false, erreur: false }).is_err() {
+                    self.fermer();  // Le lecteur a ete abandonne
+                }
+            },
+            Err(e) => {
+                warn!("SortieTee.envoyer Erreur ecriture debordement {:?} : {:?}", self.path_debordement, e);
+                self.terminer(true);
+                self.fermer();
+            }
+        }
+    }
+
+    fn terminer(&mut self, erreur: bool) {
+        self.memoire = None;
+        let _ = self.etat.send(EtatDebordement { ecrits: self.ecrits, termine: true, erreur });
+    }
+
+    fn fermer(&mut self) {
+        self.fermee = true;
+        self.memoire = None;
+        self.debordement = None;
+    }
+}
+
+impl LecteurTee {
+    async fn prochain(&mut self) -> Option<Result<Vec<u8>, std::io::Error>> {
+        if let Some(rx) = self.memoire.as_mut() {
+            match rx.recv().await {
+                Some(data) => return Some(Ok(data)),
+                None => self.memoire = None
+            }
+        }
+
+        loop {
+            let etat = *self.etat.borrow();
+            if self.lus < etat.ecrits {
+                return Some(self.lire_debordement(etat.ecrits).await)
+            }
+            if etat.erreur {
+                return Some(Err(std::io::Error::new(ErrorKind::Other, "tee_attachment Erreur lecture du fichier local")))
+            }
+            if etat.termine {
+                return None
+            }
+            if self.etat.changed().await.is_err() {
+                return Some(Err(std::io::Error::new(ErrorKind::Other, "tee_attachment Distributeur arrete")))
+            }
+        }
+    }
+
+    async fn lire_debordement(&mut self, ecrits: u64) -> Result<Vec<u8>, std::io::Error> {
+        if self.fichier.is_none() {
+            self.fichier = Some(File::open(&self.path_debordement).await?);
+        }
+        let fichier = self.fichier.as_mut().expect("fichier debordement");
+
+        let taille = ((ecrits - self.lus) as usize).min(TAILLE_CHUNK);
+        let mut buf = vec![0u8; taille];
+        fichier.read_exact(&mut buf).await?;
+        self.lus += taille as u64;
+        Ok(buf)
+    }
+}
+
+impl Drop for LecteurTee {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path_debordement) {
+            if e.kind() != ErrorKind::NotFound {
+                warn!("LecteurTee Erreur suppression {:?} : {:?}", self.path_debordement, e);
+            }
+        }
+    }
+}
@@ -1,12 +1,17 @@
 use log::{debug, error, info, warn};
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::error::Error;
+use std::fmt;
 use std::io::ErrorKind;
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::{Context, Poll};
-use tokio_util::io::StreamReader;
+use std::time::Instant;
 
 use millegrilles_common_rust::certificats::ValidateurX509;
 use millegrilles_common_rust::configuration::IsConfigNoeud;
+use millegrilles_common_rust::formatteur_messages::DateEpochSeconds;
 use millegrilles_common_rust::generateur_messages::{GenerateurMessages, RoutageMessageAction};
 use millegrilles_common_rust::{futures_util, reqwest};
 use millegrilles_common_rust::futures::Stream;
@@ -16,22 +21,101 @@ use millegrilles_common_rust::futures_util::{StreamExt, TryStream};
 use millegrilles_common_rust::futures_util::io::BufWriter;
 use millegrilles_common_rust::futures_util::sink::Buffer;
 use millegrilles_common_rust::hachages::Hacheur;
+use millegrilles_common_rust::multibase;
 use millegrilles_common_rust::multibase::Base;
-use millegrilles_common_rust::multihash::Code;
-use millegrilles_common_rust::reqwest::{Body, Request, Response, Url};
+use millegrilles_common_rust::multihash::{Code, Multihash};
+use millegrilles_common_rust::reqwest::{Body, Client, Request, Response, StatusCode, Url};
 // for map_err
 use millegrilles_common_rust::tokio::io::{AsyncReadExt};
-
+use millegrilles_common_rust::tokio::spawn;
+use millegrilles_common_rust::tokio::task::JoinHandle;
+use millegrilles_common_rust::tokio::time::{Duration, sleep};
+use millegrilles_common_rust::futures::future::join;
+use millegrilles_common_rust::futures::stream::FuturesUnordered;
+
+use crate::annulations::ErreurAnnulee;
+use crate::clients_remote::est_erreur_connexion;
 use crate::constantes::*;
+use crate::debit::LimiteurDebit;
+use crate::delais_remote::{est_status_ralentir, lire_retry_after};
 use crate::gestionnaire::{GestionnairePostmaster, new_client_local};
 use crate::messages_struct::*;
+use crate::progres_uploads::{ProgresUpload, ProgresUploads};
+use crate::source_attachment::FluxAttachment;
+use crate::tee_attachment::preparer_tee;
 
 const BUFFER_SIZE: u32 = 131072;
 const MESSAGE_SIZE_LIMIT: usize = 1 * 1024 * 1024;
+const PARTS_PARALLELES: usize = 4;
+const TENTATIVES_PART: u32 = 3;
+const INTERVALLE_PROGRES_SECS: u64 = 5;
+const ATTENTE_MAX_PART_SECS: u32 = 30;
+
+/// Le contenu du fichier local ne correspond pas a son fuuid.
+#[derive(Debug)]
+pub struct ErreurHachageInvalide {
+    pub fuuid: String,
+    pub hachage_calcule: String,
+}
 
+impl fmt::Display for ErreurHachageInvalide {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Hachage invalide pour fuuid {} (calcule : {})", self.fuuid, self.hachage_calcule)
+    }
+}
+
+impl Error for ErreurHachageInvalide {}
+
+/// Erreur de la millegrille distante durant l'upload. Le status est None pour une erreur de transport.
+/// retry_after est le delai demande par la millegrille distante (429/503).
+#[derive(Debug)]
+pub struct ErreurUploadRemote {
+    pub status: Option<u16>,
+    pub message: String,
+    pub retry_after: Option<u32>,
+}
+
+impl ErreurUploadRemote {
+    fn from_reponse(reponse: &Response, message: String) -> Self {
+        let retry_after = match est_status_ralentir(reponse.status()) {
+            true => Some(lire_retry_after(reponse.headers())),
+            false => None
+        };
+        ErreurUploadRemote { status: Some(reponse.status().as_u16()), message, retry_after }
+    }
+}
+
+impl fmt::Display for ErreurUploadRemote {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.status {
+            Some(s) => write!(f, "{} (http status {})", self.message, s),
+            None => write!(f, "{}", self.message)
+        }
+    }
+}
+
+impl Error for ErreurUploadRemote {}
+
+/// Echec de l'upload sur tous les URLs de la millegrille distante.
+#[derive(Debug)]
+pub struct ErreurFailover {
+    pub urls: Vec<String>,
+    pub derniere_erreur: String,
+}
+
+impl fmt::Display for ErreurFailover {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Upload echoue sur {:?}, derniere erreur : {}", self.urls, self.derniere_erreur)
+    }
+}
+
+impl Error for ErreurFailover {}
+
+/// Upload d'un attachment. Retourne le code de l'evenement final emis (CODE_UPLOAD_TERMINE,
+/// CODE_UPLOAD_ERREUR, CODE_UPLOAD_CORROMPU ou CODE_UPLOAD_ANNULE).
 pub async fn uploader_attachment<M>(
     middleware: &M, gestionnaire: &GestionnairePostmaster, fiche: &FicheMillegrilleApplication, fuuid: &str, uuid_message: &str)
-    -> Result<(), Box<dyn Error>>
+    -> Result<u32, Box<dyn Error>>
     where M: GenerateurMessages + VerificateurMessage + ValidateurX509 + IsConfigNoeud
 {
     debug!("uploader_attachment Attachment fuuid {}", fuuid);
@@ -43,25 +127,177 @@ pub async fn uploader_attachment<M>(
         emettre_evenement_upload(middleware, evenement).await?;
     }
 
-    // Creer pipeline d'upload vers le serveur distant.
-    let evenement = match transferer_fichier(middleware, gestionnaire, fiche, fuuid, uuid_message).await {
+    // Creer pipeline d'upload vers le serveur distant. Le transfert est interrompu s'il est annule.
+    let garde = gestionnaire.annulations.enregistrer(uuid_message, idmg, fuuid);
+    let resultat = garde.executer(transferer_fichier(middleware, gestionnaire, fiche, fuuid, uuid_message, None)).await;
+    let evenement = preparer_evenement_resultat(gestionnaire, idmg, fuuid, uuid_message, resultat);
+
+    let code = evenement.code;
+    emettre_evenement_upload(middleware, evenement).await?;
+
+    Ok(code)
+}
+
+/// Upload d'un attachment vers plusieurs millegrilles tierces. Le fichier local est lu une seule fois
+/// et distribue en parallele a chaque destination (URL preferee). Une destination dont l'URL
+/// preferee echoue est reprise individuellement avec failover. Retourne le code de l'evenement
+/// final emis pour chaque idmg.
+pub async fn uploader_attachment_destinations<M>(
+    middleware: &M, gestionnaire: &GestionnairePostmaster, fiches: &Vec<FicheMillegrilleApplication>, fuuid: &str, uuid_message: &str)
+    -> Result<HashMap<String, u32>, Box<dyn Error>>
+    where M: GenerateurMessages + VerificateurMessage + ValidateurX509 + IsConfigNoeud
+{
+    debug!("uploader_attachment_destinations Attachment fuuid {} vers {} destinations", fuuid, fiches.len());
+    // Chaque resultat est converti en evenement des qu'il est connu, un Box<dyn Error> (non Send)
+    // ne doit pas etre conserve durant un await.
+    let mut evenements: Vec<EvenementUploadAttachment> = Vec::new();
+
+    // Determiner l'etat de chaque destination avant d'ouvrir le fichier local
+    let mut destinations = Vec::new();
+    for fiche in fiches {
+        let idmg = fiche.idmg.as_str();
+        let evenement = EvenementUploadAttachment::nouveau(uuid_message.into(), idmg.into(), fuuid.into());
+        emettre_evenement_upload(middleware, evenement).await?;
+
+        let client = match gestionnaire.clients_remote.get_client(fiche) {
+            Ok(c) => c,
+            Err(e) => {
+                evenements.push(preparer_evenement_resultat(gestionnaire, idmg, fuuid, uuid_message, Err(e)));
+                continue
+            }
+        };
+        let url = match gestionnaire.endpoints.candidats(fiche, None).into_iter().next() {
+            Some(u) => u,
+            None => {
+                let erreur = format!("transfert_fichier.uploader_attachment_destinations Aucun URL pour {}", idmg);
+                evenements.push(preparer_evenement_resultat(gestionnaire, idmg, fuuid, uuid_message, Err(erreur.into())));
+                continue
+            }
+        };
+        let position_reprise = match get_etat_upload_remote(&client, url.as_str(), fuuid).await {
+            EtatUploadRemote::Complet => {
+                evenements.push(preparer_evenement_resultat(gestionnaire, idmg, fuuid, uuid_message, Ok(200)));
+                continue
+            },
+            EtatUploadRemote::Partiel(position) => position,
+            EtatUploadRemote::Absent => 0,
+        };
+        destinations.push((fiche, client, url, position_reprise));
+    }
+
+    let flux_local = match destinations.is_empty() {
+        true => None,
+        false => match ouvrir_source_locale(gestionnaire, fuuid).await {
+            Ok(f) => Some(f),
+            Err(e) => {
+                error!("uploader_attachment_destinations Erreur ouverture fichier local {} : {:?}", fuuid, e);
+                for (fiche, _, _, _) in &destinations {
+                    let erreur = format!("transfert_fichier.uploader_attachment_destinations Erreur ouverture fichier local {}", fuuid);
+                    evenements.push(preparer_evenement_resultat(
+                        gestionnaire, fiche.idmg.as_str(), fuuid, uuid_message, Err(erreur.into())));
+                }
+                None
+            }
+        }
+    };
+
+    if let Some(flux_local) = flux_local {
+        let noms = destinations.iter().map(|d| d.0.idmg.clone()).collect();
+        let (distributeur, flux) = preparer_tee(fuuid, &noms, flux_local.taille);
+
+        let uploads = FuturesUnordered::new();
+        for ((fiche, client, url, position_reprise), flux_destination) in destinations.iter().zip(flux.into_iter()) {
+            uploads.push(async move {
+                // Une destination annulee abandonne son flux, le distributeur la ferme sans bloquer les autres
+                let garde = gestionnaire.annulations.enregistrer(uuid_message, fiche.idmg.as_str(), fuuid);
+                let resultat = garde.executer(uploader_flux(
+                    middleware, gestionnaire, client, fiche, fuuid, uuid_message, url.as_str(), flux_destination, *position_reprise)).await;
+                match resultat {
+                    Ok(_) => gestionnaire.endpoints.set_prefere(fiche.idmg.as_str(), url.as_str()),
+                    Err(ref e) if est_erreur_failover(e.as_ref()) => {
+                        warn!("uploader_attachment_destinations Echec upload {} vers {}, reprise avec failover : {}", fuuid, url, e);
+                        return ResultatTee::Failover(fiche, url.as_str())
+                    },
+                    Err(_) => ()
+                }
+                ResultatTee::Termine(preparer_evenement_resultat(gestionnaire, fiche.idmg.as_str(), fuuid, uuid_message, resultat))
+            });
+        }
+
+        let (_, resultats_uploads) = join(distributeur.distribuer(flux_local.reader), uploads.collect::<Vec<_>>()).await;
+
+        // Reprendre les destinations en echec sur les autres URLs, le fichier local est relu
+        let mut failover = Vec::new();
+        for resultat in resultats_uploads {
+            match resultat {
+                ResultatTee::Termine(evenement) => evenements.push(evenement),
+                ResultatTee::Failover(fiche, url) => failover.push((fiche, url))
+            }
+        }
+        for (fiche, url) in failover {
+            let idmg = fiche.idmg.as_str();
+            let garde = gestionnaire.annulations.enregistrer(uuid_message, idmg, fuuid);
+            let resultat = garde.executer(transferer_fichier(middleware, gestionnaire, fiche, fuuid, uuid_message, Some(url))).await;
+            evenements.push(preparer_evenement_resultat(gestionnaire, idmg, fuuid, uuid_message, resultat));
+        }
+    }
+
+    let mut codes = HashMap::new();
+    for evenement in evenements {
+        codes.insert(evenement.idmg.clone(), evenement.code);
+        emettre_evenement_upload(middleware, evenement).await?;
+    }
+
+    Ok(codes)
+}
+
+/// Resultat de l'upload d'une destination alimentee par le tee.
+enum ResultatTee<'a> {
+    Termine(EvenementUploadAttachment),
+    /// L'URL preferee a echoue, la destination doit etre reprise avec failover.
+    Failover(&'a FicheMillegrilleApplication, &'a str),
+}
+
+/// Prepare l'evenement final d'un upload (complete, corrompu, annule ou erreur).
+fn preparer_evenement_resultat(
+    gestionnaire: &GestionnairePostmaster, idmg: &str, fuuid: &str, uuid_message: &str, resultat: Result<u16, Box<dyn Error>>)
+    -> EvenementUploadAttachment
+{
+    match resultat {
         Ok(status_code) => {
             // Emettre evenement de confirmation d'upload complete
             EvenementUploadAttachment::complete(uuid_message.into(), idmg.into(), fuuid.into(), status_code)
         },
+        Err(e) if e.downcast_ref::<ErreurAnnulee>().is_some() => {
+            info!("preparer_evenement_resultat Upload {} vers {} annule", fuuid, idmg);
+            EvenementUploadAttachment::annule(uuid_message.into(), idmg.into(), fuuid.into())
+        },
+        Err(e) if e.downcast_ref::<ErreurHachageInvalide>().is_some() => {
+            error!("preparer_evenement_resultat Fichier local corrompu, upload abandonne : {}", e);
+            EvenementUploadAttachment::corrompu(uuid_message.into(), idmg.into(), fuuid.into())
+        },
         Err(e) => {
-            error!("uploader_attachment Erreur transferer fichier : {:?}", e);
+            error!("preparer_evenement_resultat Erreur transferer fichier : {:?}", e);
             // Emettre evenement d'erreur d'upload de fichier (incomplet, retry plus tard)
-            EvenementUploadAttachment::erreur(uuid_message.into(), idmg.into(), fuuid.into(), 500)
+            let mut evenement = EvenementUploadAttachment::erreur(uuid_message.into(), idmg.into(), fuuid.into(), 500);
+            if let Some(erreur_failover) = e.downcast_ref::<ErreurFailover>() {
+                // Aucun URL n'a fonctionne, la fiche est peut-etre perimee
+                gestionnaire.cache_fiches.invalider(idmg);
+                evenement.urls_essayees = Some(erreur_failover.urls.clone());
+            }
+            if let Some(erreur_remote) = e.downcast_ref::<ErreurUploadRemote>() {
+                evenement.http_status = erreur_remote.status.or(evenement.http_status);
+                if let Some(retry_after) = erreur_remote.retry_after {
+                    gestionnaire.delais_remote.reporter(idmg, retry_after);
+                    evenement.retry_after = Some(retry_after);
+                }
+            }
+            evenement
         }
-    };
-
-    emettre_evenement_upload(middleware, evenement).await?;
-
-    Ok(())
+    }
 }
 
-async fn emettre_evenement_upload<M>(middleware: &M, evenement: EvenementUploadAttachment)
+pub async fn emettre_evenement_upload<M>(middleware: &M, evenement: EvenementUploadAttachment)
     -> Result<(), Box<dyn Error>>
     where M: GenerateurMessages
 {
@@ -70,72 +306,115 @@ async fn emettre_evenement_upload<M>(middleware: &M, evenement: EvenementUploadA
     Ok(())
 }
 
-async fn transferer_fichier<M>(middleware: &M, gestionnaire: &GestionnairePostmaster, fiche: &FicheMillegrilleApplication, fuuid: &str, uuid_message: &str)
+/// Upload avec failover sur les URLs candidats de la fiche. url_exclue est un URL qui vient d'echouer.
+async fn transferer_fichier<M>(
+    middleware: &M, gestionnaire: &GestionnairePostmaster, fiche: &FicheMillegrilleApplication, fuuid: &str, uuid_message: &str,
+    url_exclue: Option<&str>
+)
     -> Result<u16, Box<dyn Error>>
     where M: ValidateurX509 + GenerateurMessages + IsConfigNoeud
 {
-    for app in &fiche.application {
-        let url = app.url.as_str();
-
-        // Ouvrir reader aupres de la millegrille locale
-        let response_local = connecter_local(middleware, gestionnaire, fuuid).await?;
-        debug!("Reponse local : {:?}", response_local);
-        let taille_fichier = match response_local.headers().get("content-length") {
-            Some(cl) => {
-                debug!("Content-Length : {:?}", cl);
-                match cl.to_str() {
-                    Ok(len) => {
-                        match len.parse::<usize>() {
-                            Ok(len_usize) => Some(len_usize),
-                            Err(e) => None
-                        }
-                    },
-                    Err(_e) => None,
-                }
-            },
-            None => None
-        };
-        debug!("Traitement fichier taille : {:?}", taille_fichier);
-        let mut handler = UploadHandler { taille: taille_fichier };
-        let status_code = handler.upload(gestionnaire, response_local, fuuid, url).await?;
+    let client_remote = gestionnaire.clients_remote.get_client(fiche)?;
+    let idmg = fiche.idmg.as_str();
 
-        return Ok(status_code)
+    let mut urls_essayees: Vec<String> = url_exclue.into_iter().map(|u| u.to_owned()).collect();
+    let mut derniere_erreur = String::from("aucun URL d'application dans la fiche");
+    for url in gestionnaire.endpoints.candidats(fiche, None) {
+        if Some(url.as_str()) == url_exclue { continue }
+        urls_essayees.push(url.clone());
+        match transferer_fichier_url(middleware, gestionnaire, &client_remote, fiche, fuuid, uuid_message, url.as_str()).await {
+            Ok(status_code) => {
+                gestionnaire.endpoints.set_prefere(idmg, url.as_str());
+                return Ok(status_code)
+            },
+            Err(e) => {
+                if ! est_erreur_failover(e.as_ref()) {
+                    return Err(e)
+                }
+                warn!("transferer_fichier Echec upload {} vers {}, essai du prochain URL : {}", fuuid, url, e);
+                derniere_erreur = e.to_string();
+            }
+        }
     }
 
-    Err(format!("Erreur transfert fichier, aucun upload succes"))?
+    Err(ErreurFailover { urls: urls_essayees, derniere_erreur })?
 }
 
-async fn connecter_local<M>(middleware: &M, gestionnaire: &GestionnairePostmaster, fuuid: &str)
-    -> Result<Response, Box<dyn Error>>
+/// Indique si l'erreur justifie d'essayer le prochain URL (connexion, timeout ou 5xx). Une demande
+/// de ralentir (Retry-After) s'applique a toute la millegrille distante.
+fn est_erreur_failover(err: &(dyn Error + 'static)) -> bool {
+    if est_erreur_connexion(err) {
+        return true
+    }
+    match err.downcast_ref::<ErreurUploadRemote>() {
+        Some(e) if e.retry_after.is_some() => false,
+        Some(e) => match e.status { Some(s) => s >= 500, None => true },
+        None => false
+    }
+}
+
+/// Upload du fichier vers un URL de la millegrille distante. Le stream local est ouvert a chaque essai.
+async fn transferer_fichier_url<M>(
+    middleware: &M, gestionnaire: &GestionnairePostmaster, client_remote: &Client, fiche: &FicheMillegrilleApplication,
+    fuuid: &str, uuid_message: &str, url: &str
+)
+    -> Result<u16, Box<dyn Error>>
     where M: ValidateurX509 + GenerateurMessages + IsConfigNoeud
 {
-    let client_interne = gestionnaire.http_client_local.as_ref().expect("client reqwest fichiers locaux");
-
-    let url_get_fichier = match &middleware.get_configuration_noeud().fichiers_url {
-        Some(u) => {
-            let mut url_get_fichier = u.clone();
-            let url_liste_fichiers_str = format!("/fichiers/{}", fuuid);
-            url_get_fichier.set_path(url_liste_fichiers_str.as_str());
-            url_get_fichier
+    // Verifier si la millegrille distante a deja recu le fichier ou une partie du fichier
+    let position_reprise = match get_etat_upload_remote(client_remote, url, fuuid).await {
+        EtatUploadRemote::Complet => {
+            info!("transferer_fichier_url Fichier {} deja present sur {}, upload saute", fuuid, url);
+            return Ok(200)
         },
-        None => Err(format!("transfert_fichier.transferer_fichier URL fichiers n'est pas disponible"))?
+        EtatUploadRemote::Partiel(position) => position,
+        EtatUploadRemote::Absent => 0,
     };
 
-    let request_get = client_interne.get(url_get_fichier);
-    let reponse = request_get.send().await?;
-    debug!("transferer_fichier transferer_fichier Reponse : {:?}", reponse);
-    if !reponse.status().is_success() {
-        Err(format!("transfert_fichier.transferer_fichier Erreur ouverture fichier status {} : {}", reponse.status().as_u16(), reponse.url().as_str()))?
-    }
+    // Ouvrir reader aupres de la millegrille locale. Le fichier est toujours lu au complet pour
+    // verifier le hachage, les bytes deja recus par la millegrille distante sont sautes.
+    // Une erreur locale ne doit pas declencher le failover vers un autre URL distant.
+    let flux_local = ouvrir_source_locale(gestionnaire, fuuid).await
+        .map_err(|e| format!("transfert_fichier.transferer_fichier_url Erreur ouverture fichier local {} : {:?}", fuuid, e))?;
 
-    Ok(reponse)
+    uploader_flux(middleware, gestionnaire, client_remote, fiche, fuuid, uuid_message, url, flux_local, position_reprise).await
 }
 
-async fn connecter_remote(gestionnaire: &GestionnairePostmaster, url: &str, fuuid: &str, position: Option<usize>, stream: Body)
-    -> Result<Response, Box<dyn Error>>
+async fn uploader_flux<M>(
+    middleware: &M, gestionnaire: &GestionnairePostmaster, client_remote: &Client, fiche: &FicheMillegrilleApplication,
+    fuuid: &str, uuid_message: &str, url: &str, flux_local: FluxAttachment, position_reprise: usize
+)
+    -> Result<u16, Box<dyn Error>>
+    where M: GenerateurMessages
+{
+    let taille_fichier = flux_local.taille;
+    debug!("Traitement fichier taille : {:?}, reprise a {}", taille_fichier, position_reprise);
+    let suivi = SuiviProgres::new(
+        gestionnaire.progres_uploads.clone(), uuid_message, fiche.idmg.as_str(), fuuid, taille_fichier, position_reprise);
+    let mut handler = UploadHandler {
+        taille: taille_fichier,
+        client: client_remote.clone(),
+        idmg: fiche.idmg.clone(),
+        debit: gestionnaire.debit.clone(),
+        suivi,
+    };
+    let status_code = handler.upload(middleware, flux_local, fuuid, url, position_reprise).await?;
+
+    Ok(status_code)
+}
+
+async fn ouvrir_source_locale(gestionnaire: &GestionnairePostmaster, fuuid: &str)
+    -> Result<FluxAttachment, Box<dyn Error>>
 {
-    let client = gestionnaire.http_client_remote.as_ref().expect("client reqwest fichiers remote");
+    match gestionnaire.source_attachments.as_ref() {
+        Some(source) => source.ouvrir(fuuid).await,
+        None => Err(format!("transfert_fichier.ouvrir_source_locale Aucune source d'attachments configuree"))?
+    }
+}
 
+async fn connecter_remote(client: &Client, url: &str, fuuid: &str, position: Option<usize>, stream: Body)
+    -> Result<Response, Box<dyn Error>>
+{
     let mut url_put_fichier = Url::parse(url)?;
     let url_liste_fichiers_str = match position {
         Some(p) => format!("{}/poster/{}/{}", url_put_fichier.path(), fuuid, p),
@@ -151,114 +430,413 @@ async fn connecter_remote(gestionnaire: &GestionnairePostmaster, url: &str, fuui
     Ok(response)
 }
 
-fn convert_err(err: reqwest::Error) -> std::io::Error {
-    std::io::Error::new(ErrorKind::Other, err)
+enum EtatUploadRemote {
+    Absent,
+    /// Fin des parts contigues deja recues par la millegrille distante.
+    Partiel(usize),
+    Complet,
+}
+
+/// Demande l'etat du fichier a la millegrille distante (GET /poster/{fuuid}). Une erreur est
+/// traitee comme un fichier absent.
+async fn get_etat_upload_remote(client: &Client, url: &str, fuuid: &str) -> EtatUploadRemote {
+    let mut url_etat = match Url::parse(url) {
+        Ok(u) => u,
+        Err(_) => return EtatUploadRemote::Absent
+    };
+    let path_etat = format!("{}/poster/{}", url_etat.path(), fuuid);
+    url_etat.set_path(path_etat.as_str());
+
+    let reponse = match client.get(url_etat).send().await {
+        Ok(r) => r,
+        Err(e) => {
+            debug!("get_etat_upload_remote Erreur requete etat upload {} : {:?}", fuuid, e);
+            return EtatUploadRemote::Absent
+        }
+    };
+    if ! reponse.status().is_success() {
+        return EtatUploadRemote::Absent
+    }
+
+    let etat: ReponseEtatUploadPartiel = match reponse.json().await {
+        Ok(e) => e,
+        Err(e) => {
+            debug!("get_etat_upload_remote Reponse etat upload {} invalide : {:?}", fuuid, e);
+            return EtatUploadRemote::Absent
+        }
+    };
+
+    if etat.code == Some(CODE_FICHIER_EXISTANT) {
+        return EtatUploadRemote::Complet
+    }
+
+    match position_contigue(etat.parts.unwrap_or_default()) {
+        0 => EtatUploadRemote::Absent,
+        p => {
+            info!("get_etat_upload_remote Upload {} : {} bytes deja recus par {}", fuuid, p, url);
+            EtatUploadRemote::Partiel(p)
+        }
+    }
+}
+
+/// Nombre de bytes recus sans trou depuis le debut du fichier (parts dans n'importe quel ordre,
+/// possiblement chevauchantes).
+fn position_contigue(mut parts: Vec<PartUploadRecue>) -> usize {
+    parts.sort_by_key(|p| p.position);
+    let mut position = 0;
+    for part in parts {
+        if part.position > position { break }  // Trou dans les parts recues
+        position = position.max(part.position + part.taille);
+    }
+    position
+}
+
+/// Prepare un hacheur avec l'algorithme et l'encodage du fuuid (multibase de multihash).
+fn preparer_hacheur(fuuid: &str) -> Result<Hacheur, Box<dyn Error>> {
+    let (base, bytes) = multibase::decode(fuuid)?;
+    let multihash = Multihash::from_bytes(bytes.as_slice())?;
+    let code = Code::try_from(multihash.code())?;
+    Ok(Hacheur::builder().digester(code).base(base).build())
+}
+
+fn verifier_hachage(hacheur: &mut Hacheur, fuuid: &str) -> Result<(), Box<dyn Error>> {
+    let hachage_calcule = hacheur.finalize();
+    if hachage_calcule.as_str() != fuuid {
+        Err(ErreurHachageInvalide { fuuid: fuuid.into(), hachage_calcule })?
+    }
+    Ok(())
+}
+
+/// Suivi du progres d'un upload. Le progres est publie dans le registre des uploads en cours
+/// (retire au drop) et emis periodiquement en evenement.
+struct SuiviProgres {
+    registre: Arc<ProgresUploads>,
+    id_registre: u64,
+    uuid_message: String,
+    idmg: String,
+    fuuid: String,
+    taille: Option<usize>,
+    position: usize,
+    position_depart: usize,
+    debut: Instant,
+    dernier_evenement: Instant,
+}
+
+impl SuiviProgres {
+    fn new(registre: Arc<ProgresUploads>, uuid_message: &str, idmg: &str, fuuid: &str, taille: Option<usize>, position_depart: usize) -> Self {
+        let maintenant = Instant::now();
+        let id_registre = registre.ajouter(ProgresUpload {
+            uuid_message: uuid_message.into(),
+            idmg: idmg.into(),
+            fuuid: fuuid.into(),
+            position: position_depart,
+            taille,
+            debit: 0,
+            date_debut: DateEpochSeconds::now(),
+        });
+        SuiviProgres {
+            registre,
+            id_registre,
+            uuid_message: uuid_message.into(),
+            idmg: idmg.into(),
+            fuuid: fuuid.into(),
+            taille,
+            position: position_depart,
+            position_depart,
+            debut: maintenant,
+            dernier_evenement: maintenant,
+        }
+    }
+
+    /// Ajoute des bytes confirmes. Retourne un evenement si l'intervalle depuis le dernier est ecoule.
+    fn ajouter(&mut self, bytes: usize) -> Option<EvenementUploadAttachment> {
+        self.position += bytes;
+
+        let maintenant = Instant::now();
+        let duree_ms = maintenant.duration_since(self.debut).as_millis().max(1);
+        let debit = ((self.position - self.position_depart) as u128 * 1000 / duree_ms) as u64;
+        self.registre.maj(self.id_registre, self.position, debit);
+
+        if maintenant.duration_since(self.dernier_evenement) < Duration::from_secs(INTERVALLE_PROGRES_SECS) {
+            return None
+        }
+        self.dernier_evenement = maintenant;
+
+        Some(EvenementUploadAttachment::progres(
+            self.uuid_message.clone(), self.idmg.clone(), self.fuuid.clone(), self.position, self.taille, debit))
+    }
+
+    async fn progres<M>(&mut self, middleware: &M, bytes: usize)
+        where M: GenerateurMessages
+    {
+        if let Some(evenement) = self.ajouter(bytes) {
+            debug!("SuiviProgres Upload {} : {}/{:?} bytes", self.fuuid, self.position, self.taille);
+            // Un evenement de progres perdu n'interrompt pas l'upload
+            if let Err(e) = emettre_evenement_upload(middleware, evenement).await {
+                warn!("SuiviProgres Erreur emission evenement progres {} : {:?}", self.fuuid, e);
+            }
+        }
+    }
+}
+
+impl Drop for SuiviProgres {
+    fn drop(&mut self) {
+        self.registre.retirer(self.id_registre);
+    }
 }
 
 struct UploadHandler {
     taille: Option<usize>,
+    client: Client,
+    idmg: String,
+    debit: Arc<LimiteurDebit>,
+    suivi: SuiviProgres,
 }
 
 impl UploadHandler {
-    async fn upload(&self, gestionnaire: &GestionnairePostmaster, response_local: Response, fuuid: &str, url: &str) -> Result<u16, Box<dyn Error>> {
+    async fn upload<M>(&mut self, middleware: &M, flux_local: FluxAttachment, fuuid: &str, url: &str, position_reprise: usize)
+        -> Result<u16, Box<dyn Error>>
+        where M: GenerateurMessages
+    {
         let split = match self.taille { Some(t) => t >= MESSAGE_SIZE_LIMIT, None => true };
 
-        match split {
-            true => self.upload_split(gestionnaire, response_local, fuuid, url).await,
-            false => self.upload_simple(gestionnaire, response_local, fuuid, url).await
+        // Une reprise se fait toujours par parts
+        match split || position_reprise > 0 {
+            true => self.upload_split(middleware, flux_local, fuuid, url, position_reprise).await,
+            false => self.upload_simple(middleware, flux_local, fuuid, url).await
         }
     }
 
-    async fn upload_simple(&self, gestionnaire: &GestionnairePostmaster, response_local: Response, fuuid: &str, url: &str) -> Result<u16, Box<dyn Error>> {
-        let body_stream = reqwest::Body::wrap_stream(response_local.bytes_stream());
-        let reponse = connecter_remote(gestionnaire, url, fuuid, None, body_stream).await?;
+    /// Upload d'un petit fichier en une seule requete. Le contenu est lu en memoire pour verifier
+    /// le hachage avant l'envoi.
+    async fn upload_simple<M>(&mut self, middleware: &M, mut flux_local: FluxAttachment, fuuid: &str, url: &str)
+        -> Result<u16, Box<dyn Error>>
+        where M: GenerateurMessages
+    {
+        let mut hacheur = preparer_hacheur(fuuid)?;
+        let mut contenu = Vec::new();
+        flux_local.reader.read_to_end(&mut contenu).await?;
+        hacheur.update(contenu.as_slice());
+        verifier_hachage(&mut hacheur, fuuid)?;
+
+        let taille_contenu = contenu.len();
+        self.debit.consommer(self.idmg.as_str(), taille_contenu).await;
+        let reponse = connecter_remote(&self.client, url, fuuid, None, Body::from(contenu)).await?;
         if ! reponse.status().is_success() {
-            Err(format!("Erreur upload code {}", reponse.status().as_u16()))?
+            Err(ErreurUploadRemote::from_reponse(&reponse, format!("Erreur upload {}", fuuid)))?
         }
+        self.suivi.progres(middleware, taille_contenu).await;
         Ok(reponse.status().as_u16())
     }
 
-    async fn upload_split(&self, gestionnaire: &GestionnairePostmaster, response_local: Response, fuuid: &str, url: &str) -> Result<u16, Box<dyn Error>> {
-        let byte_stream = response_local.bytes_stream();
-        let mut reader = StreamReader::new(byte_stream.map_err(convert_err));
+    /// Upload par parts a partir de position_reprise. Tout le stream local est hache, les bytes
+    /// deja recus par la millegrille distante ne sont pas transmis.
+    async fn upload_split<M>(&mut self, middleware: &M, flux_local: FluxAttachment, fuuid: &str, url: &str, position_reprise: usize)
+        -> Result<u16, Box<dyn Error>>
+        where M: GenerateurMessages
+    {
+        let mut hacheur = preparer_hacheur(fuuid)?;
+        let mut reader = flux_local.reader;
 
         let mut buf = [0; 32768];
         let mut buf_bytes: Vec<u8> = Vec::new();
         buf_bytes.reserve(MESSAGE_SIZE_LIMIT);
-        let mut position: usize = 0;
+        let mut position: usize = position_reprise;
+        let mut a_sauter = position_reprise;
+        let mut parts_en_vol = PartsEnVol::new();
         loop {
             let len_read = reader.read(&mut buf).await?;
 
             // debug!("Data lu : {:?}", len_read);
             if len_read == 0 { break; }
+            hacheur.update(&buf[..len_read]);
+
+            let mut data = &buf[..len_read];
+            if a_sauter > 0 {
+                // Bytes deja recus par la millegrille distante
+                let len_saute = a_sauter.min(data.len());
+                a_sauter -= len_saute;
+                data = &data[len_saute..];
+                if data.len() == 0 { continue; }
+            }
+
+            // Limiter le debit de lecture, ce qui limite le debit des parts transmises
+            self.debit.consommer(self.idmg.as_str(), data.len()).await;
 
             let taille_buf = buf_bytes.len();
-            if taille_buf + len_read < MESSAGE_SIZE_LIMIT {
-                buf_bytes.extend(&buf[..len_read]);
+            if taille_buf + data.len() < MESSAGE_SIZE_LIMIT {
+                buf_bytes.extend(data);
             } else {
                 // Split
-                let excedent = taille_buf + len_read - MESSAGE_SIZE_LIMIT;
-                let fin_read = len_read - excedent;
-                debug!("Position {}, taille_buf {}, len_read {}, excedent {}, fin_read {}", position, taille_buf, len_read, excedent, fin_read);
-                buf_bytes.extend(&buf[..fin_read]);
+                let fin_read = MESSAGE_SIZE_LIMIT - taille_buf;
+                debug!("Position {}, taille_buf {}, len_read {}, fin_read {}", position, taille_buf, data.len(), fin_read);
+                buf_bytes.extend(&data[..fin_read]);
 
                 let position_courante = position;
                 position += buf_bytes.len();  // Incrementer position courante
 
                 debug!("Uploader buffer len {:?}", buf_bytes.len());
-                let reponse_part = self.upload_part(gestionnaire, fuuid, url, position_courante, buf_bytes).await?;
-                let status_code = reponse_part.status().as_u16();
-                if status_code == 200 {
-                    match reponse_part.json::<ResponsePutFichierPartiel>().await {
-                        Ok(r) => {
-                            if r.ok {
-                                if let Some(code) = r.code {
-                                    if code == 7 {
-                                        // Le fichier existe deja, on retourne la reponse. OK.
-                                        return Ok(200)
-                                    }
-                                }
-                            }
-                        },
-                        Err(e) => ()
-                    };
-                } else if ! reponse_part.status().is_success() {
-                    Err(format!("transfert_fichier.upload_split Echec upload fichier split {} : http status {}", fuuid, reponse_part.status().as_u16()))?;
+                while parts_en_vol.len() >= PARTS_PARALLELES {
+                    let resultat_part = parts_en_vol.attendre_prochaine().await?;
+                    match resultat_part {
+                        Some(ResultatPart::Recue(taille_part)) => self.suivi.progres(middleware, taille_part).await,
+                        // Le fichier existe deja, on retourne la reponse. OK.
+                        Some(ResultatPart::FichierExistant) => return Ok(200),
+                        None => ()
+                    }
                 }
+                parts_en_vol.ajouter(self.client.clone(), url, fuuid, position_courante, buf_bytes);
 
                 // Remettre reste du buffer
                 buf_bytes = Vec::new();
                 buf_bytes.reserve(MESSAGE_SIZE_LIMIT);
-                buf_bytes.extend(&buf[fin_read..len_read]);
+                buf_bytes.extend(&data[fin_read..]);
             }
 
         }
 
+        // Ne pas transmettre la derniere part d'un fichier corrompu (les parts en vol sont annulees)
+        verifier_hachage(&mut hacheur, fuuid)?;
+
         if buf_bytes.len() > 0 {
             debug!("upload_split Emttre derniere partie du fichier len: {:?}", buf_bytes.len());
-            self.upload_part(gestionnaire, fuuid, url, position, buf_bytes).await?;
+            parts_en_vol.ajouter(self.client.clone(), url, fuuid, position, buf_bytes);
+        }
+
+        // Attendre la confirmation de toutes les parts avant de finaliser
+        loop {
+            let resultat_part = parts_en_vol.attendre_prochaine().await?;
+            match resultat_part {
+                Some(ResultatPart::Recue(taille_part)) => self.suivi.progres(middleware, taille_part).await,
+                Some(ResultatPart::FichierExistant) => return Ok(200),
+                None => break
+            }
         }
 
-        let reponse_finale = upload_post_final(gestionnaire, url, fuuid).await?;
+        let reponse_finale = upload_post_final(&self.client, url, fuuid).await?;
         match reponse_finale.status().is_success() {
             true => Ok(reponse_finale.status().as_u16()),
-            false => Err(format!("transfert_fichier.upload_split Erreur POST upload fichier {}", reponse_finale.status().as_u16()))?
+            false => Err(ErreurUploadRemote::from_reponse(
+                &reponse_finale, format!("transfert_fichier.upload_split Erreur POST upload fichier {}", fuuid)))?
         }
     }
 
-    async fn upload_part(&self, gestionnaire: &GestionnairePostmaster, fuuid: &str, url: &str, position: usize, buffer: Vec<u8>) -> Result<Response, Box<dyn Error>> {
-        let body_stream = reqwest::Body::from(buffer);
-        let reponse = connecter_remote(gestionnaire, url, fuuid, Some(position), body_stream).await?;
-        Ok(reponse)
+}
+
+enum ResultatPart {
+    Recue(usize),
+    FichierExistant,
+}
+
+/// Parts en cours d'upload. Les uploads restants sont annules si l'upload est abandonne.
+struct PartsEnVol {
+    taches: FuturesUnordered<JoinHandle<Result<ResultatPart, ErreurUploadRemote>>>,
+}
+
+impl PartsEnVol {
+    fn new() -> Self {
+        PartsEnVol { taches: FuturesUnordered::new() }
     }
 
+    fn len(&self) -> usize {
+        self.taches.len()
+    }
+
+    fn ajouter(&mut self, client: Client, url: &str, fuuid: &str, position: usize, buffer: Vec<u8>) {
+        self.taches.push(spawn(uploader_part(client, url.to_owned(), fuuid.to_owned(), position, buffer)));
+    }
+
+    /// Attend la prochaine part confirmee, None s'il ne reste aucune part en cours.
+    async fn attendre_prochaine(&mut self) -> Result<Option<ResultatPart>, Box<dyn Error>> {
+        match self.taches.next().await {
+            Some(resultat) => Ok(Some(resultat??)),
+            None => Ok(None)
+        }
+    }
 }
 
-async fn upload_post_final(gestionnaire: &GestionnairePostmaster, url: &str, fuuid: &str)
-    -> Result<Response, Box<dyn Error>>
+impl Drop for PartsEnVol {
+    fn drop(&mut self) {
+        for tache in self.taches.iter() {
+            tache.abort();
+        }
+    }
+}
+
+/// Upload d'une part avec retry. Les erreurs 4xx ne sont pas reessayees.
+async fn uploader_part(client: Client, url: String, fuuid: String, position: usize, buffer: Vec<u8>)
+    -> Result<ResultatPart, ErreurUploadRemote>
 {
-    let client = gestionnaire.http_client_remote.as_ref().expect("client reqwest fichiers remote");
+    let mut url_put_part = Url::parse(url.as_str())
+        .map_err(|e| ErreurUploadRemote {
+            status: None,
+            message: format!("transfert_fichier.uploader_part URL invalide {} : {:?}", url, e),
+            retry_after: None,
+        })?;
+    let path_part = format!("{}/poster/{}/{}", url_put_part.path(), fuuid, position);
+    url_put_part.set_path(path_part.as_str());
+
+    let mut derniere_erreur = String::new();
+    let mut dernier_status = None;
+    for tentative in 1..=TENTATIVES_PART {
+        let resultat = client.put(url_put_part.clone())
+            .header("Content-Type", "application/stream")
+            .body(buffer.clone())
+            .send().await;
+
+        match resultat {
+            Ok(reponse) if reponse.status().is_success() => {
+                if reponse.status().as_u16() == 200 {
+                    if let Ok(r) = reponse.json::<ResponsePutFichierPartiel>().await {
+                        if r.ok && r.code == Some(CODE_FICHIER_EXISTANT) {
+                            return Ok(ResultatPart::FichierExistant)
+                        }
+                    }
+                }
+                return Ok(ResultatPart::Recue(buffer.len()))
+            },
+            Ok(reponse) if est_status_ralentir(reponse.status()) => {
+                // Attendre le delai demande s'il est court, sinon abandonner l'upload
+                let retry_after = lire_retry_after(reponse.headers());
+                if retry_after > ATTENTE_MAX_PART_SECS || tentative == TENTATIVES_PART {
+                    return Err(ErreurUploadRemote::from_reponse(
+                        &reponse, format!("transfert_fichier.uploader_part Upload part {} de {} refuse", position, fuuid)))
+                }
+                info!("uploader_part Part {} de {} : http status {}, retry dans {} secondes",
+                    position, fuuid, reponse.status().as_u16(), retry_after);
+                sleep(Duration::from_secs(retry_after as u64)).await;
+                continue
+            },
+            Ok(reponse) if reponse.status().is_client_error() => {
+                return Err(ErreurUploadRemote::from_reponse(
+                    &reponse, format!("transfert_fichier.uploader_part Echec upload part {} de {}", position, fuuid)))
+            },
+            Ok(reponse) => {
+                dernier_status = Some(reponse.status().as_u16());
+                derniere_erreur = format!("http status {}", reponse.status().as_u16());
+            },
+            Err(e) => {
+                dernier_status = None;
+                derniere_erreur = format!("{:?}", e);
+            },
+        }
+
+        warn!("uploader_part Erreur part {} de {} (tentative {}/{}) : {}", position, fuuid, tentative, TENTATIVES_PART, derniere_erreur);
+        if tentative < TENTATIVES_PART {
+            sleep(Duration::from_secs(2 * tentative as u64)).await;
+        }
+    }
+
+    Err(ErreurUploadRemote {
+        status: dernier_status,
+        message: format!("transfert_fichier.uploader_part Echec upload part {} de {} : {}", position, fuuid, derniere_erreur),
+        retry_after: None,
+    })
+}
 
+async fn upload_post_final(client: &Client, url: &str, fuuid: &str)
+    -> Result<Response, Box<dyn Error>>
+{
     let mut url_post_fichier = Url::parse(url)?;
     let url_liste_fichiers_str = format!("{}/poster/{}", url_post_fichier.path(), fuuid);
     url_post_fichier.set_path(url_liste_fichiers_str.as_str());
@@ -267,3 +845,38 @@ async fn upload_post_final(gestionnaire: &GestionnairePostmaster, url: &str, fuu
 
     Ok(response)
 }
+
+#[cfg(test)]
+mod test_position_contigue {
+    use super::*;
+    use crate::test_setup::setup;
+
+    fn parts(positions: Vec<(usize, usize)>) -> Vec<PartUploadRecue> {
+        positions.into_iter().map(|(position, taille)| PartUploadRecue { position, taille }).collect()
+    }
+
+    #[test]
+    fn test_parts_contigues() {
+        setup("test_parts_contigues");
+        assert_eq!(0, position_contigue(Vec::new()));
+        assert_eq!(300, position_contigue(parts(vec![(0, 100), (100, 100), (200, 100)])));
+        // Ordre de reception quelconque
+        assert_eq!(300, position_contigue(parts(vec![(200, 100), (0, 100), (100, 100)])));
+    }
+
+    #[test]
+    fn test_parts_avec_trou() {
+        setup("test_parts_avec_trou");
+        assert_eq!(200, position_contigue(parts(vec![(0, 100), (100, 100), (300, 100)])));
+        assert_eq!(100, position_contigue(parts(vec![(400, 100), (0, 100), (200, 100)])));
+        // Premiere part absente
+        assert_eq!(0, position_contigue(parts(vec![(100, 100), (200, 100)])));
+    }
+
+    #[test]
+    fn test_parts_chevauchantes() {
+        setup("test_parts_chevauchantes");
+        assert_eq!(250, position_contigue(parts(vec![(0, 200), (100, 50), (150, 100)])));
+        assert_eq!(200, position_contigue(parts(vec![(0, 100), (0, 200)])));
+    }
+}